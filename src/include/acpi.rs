@@ -0,0 +1,202 @@
+use crate::include::multiboot;
+use spin::Mutex;
+
+const MULTIBOOT_TAG_ACPI_OLD: u32 = 14;
+const MULTIBOOT_TAG_ACPI_NEW: u32 = 15;
+
+const MAX_IO_APICS: usize = 4;
+const MAX_OVERRIDES: usize = 16;
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+
+/// MADT flags: the system also has dual 8259 PICs.
+const MADT_PCAT_COMPAT: u32 = 0x1;
+
+#[repr(C, packed)]
+struct Rsdp {
+	signature: [u8; 8],
+	checksum: u8,
+	oem_id: [u8; 6],
+	revision: u8,
+	rsdt_address: u32,
+}
+
+#[repr(C, packed)]
+pub struct SdtHeader {
+	pub signature: [u8; 4],
+	pub length: u32,
+	revision: u8,
+	checksum: u8,
+	oem_id: [u8; 6],
+	oem_table_id: [u8; 8],
+	oem_revision: u32,
+	creator_id: u32,
+	creator_revision: u32,
+}
+
+#[repr(C, packed)]
+struct MadtHeader {
+	header: SdtHeader,
+	local_apic_address: u32,
+	flags: u32,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+	pub id: u8,
+	pub address: u32,
+	pub gsi_base: u32,
+}
+
+/// ISA IRQ `source` is wired to global system interrupt `gsi`. \
+/// `flags` bits 0-1: polarity, bits 2-3: trigger mode (0 = bus default).
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+	pub source: u8,
+	pub gsi: u32,
+	pub flags: u16,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+	pub local_apic_address: usize,
+	pub legacy_pics: bool,
+	pub cpu_count: usize,
+	pub io_apics: [IoApicEntry; MAX_IO_APICS],
+	pub io_apic_count: usize,
+	pub overrides: [InterruptOverride; MAX_OVERRIDES],
+	pub override_count: usize,
+}
+
+impl Madt {
+	pub fn io_apics(&self) -> &[IoApicEntry] {
+		&self.io_apics[..self.io_apic_count]
+	}
+
+	pub fn overrides(&self) -> &[InterruptOverride] {
+		&self.overrides[..self.override_count]
+	}
+}
+
+pub static MADT: Mutex<Option<Madt>> = Mutex::new(None);
+
+fn checksum(address: usize, length: usize) -> bool {
+	let bytes = unsafe { core::slice::from_raw_parts(address as *const u8, length) };
+	bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Find the RSDT address from the RSDP copy GRUB puts in the Multiboot2 info.
+fn find_rsdt(multiboot_info: usize) -> Option<usize> {
+	let tag = multiboot::parse_multiboot_info(multiboot_info, MULTIBOOT_TAG_ACPI_OLD)
+		.or_else(|| multiboot::parse_multiboot_info(multiboot_info, MULTIBOOT_TAG_ACPI_NEW))?;
+	// tag type (u32), tag size (u32), then the RSDP
+	let rsdp_address = tag as usize + 8;
+	let rsdp = unsafe { &*(rsdp_address as *const Rsdp) };
+	if &rsdp.signature != b"RSD PTR " || !checksum(rsdp_address, core::mem::size_of::<Rsdp>()) {
+		return None;
+	}
+	Some(rsdp.rsdt_address as usize)
+}
+
+/// ## Find_table
+/// Look for an ACPI table by signature in the RSDT. \
+/// Tables are read at their physical address, call it before paging or with them mapped.
+pub fn find_table(rsdt: usize, signature: &[u8; 4]) -> Option<*const SdtHeader> {
+	let header = unsafe { &*(rsdt as *const SdtHeader) };
+	if &header.signature != b"RSDT" || !checksum(rsdt, header.length as usize) {
+		return None;
+	}
+	let entries = (header.length as usize - core::mem::size_of::<SdtHeader>()) / 4;
+	let first = rsdt + core::mem::size_of::<SdtHeader>();
+
+	(0..entries)
+		.map(|i| unsafe { core::ptr::read_unaligned((first + i * 4) as *const u32) } as usize)
+		.find(|&table| {
+			let table_header = unsafe { &*(table as *const SdtHeader) };
+			&table_header.signature == signature && checksum(table, table_header.length as usize)
+		})
+		.map(|table| table as *const SdtHeader)
+}
+
+fn parse_madt(table: *const SdtHeader) -> Madt {
+	let mut madt = Madt {
+		local_apic_address: 0,
+		legacy_pics: false,
+		cpu_count: 0,
+		io_apics: [IoApicEntry {
+			id: 0,
+			address: 0,
+			gsi_base: 0,
+		}; MAX_IO_APICS],
+		io_apic_count: 0,
+		overrides: [InterruptOverride {
+			source: 0,
+			gsi: 0,
+			flags: 0,
+		}; MAX_OVERRIDES],
+		override_count: 0,
+	};
+	unsafe {
+		let header = &*(table as *const MadtHeader);
+		madt.local_apic_address = header.local_apic_address as usize;
+		madt.legacy_pics = header.flags & MADT_PCAT_COMPAT != 0;
+
+		let mut entry = table as usize + core::mem::size_of::<MadtHeader>();
+		let end = table as usize + header.header.length as usize;
+		while entry + 2 <= end {
+			let entry_type = *(entry as *const u8);
+			let length = *((entry + 1) as *const u8) as usize;
+			if length < 2 {
+				break;
+			}
+			match entry_type {
+				MADT_LOCAL_APIC => {
+					// acpi id (u8), apic id (u8), flags (u32): bit 0 enabled
+					let flags = core::ptr::read_unaligned((entry + 4) as *const u32);
+					if flags & 0x1 != 0 {
+						madt.cpu_count += 1;
+					}
+				}
+				MADT_IO_APIC if madt.io_apic_count < MAX_IO_APICS => {
+					madt.io_apics[madt.io_apic_count] = IoApicEntry {
+						id: *((entry + 2) as *const u8),
+						address: core::ptr::read_unaligned((entry + 4) as *const u32),
+						gsi_base: core::ptr::read_unaligned((entry + 8) as *const u32),
+					};
+					madt.io_apic_count += 1;
+				}
+				MADT_INTERRUPT_OVERRIDE if madt.override_count < MAX_OVERRIDES => {
+					madt.overrides[madt.override_count] = InterruptOverride {
+						source: *((entry + 3) as *const u8),
+						gsi: core::ptr::read_unaligned((entry + 4) as *const u32),
+						flags: core::ptr::read_unaligned((entry + 8) as *const u16),
+					};
+					madt.override_count += 1;
+				}
+				MADT_LOCAL_APIC_OVERRIDE => {
+					let address = core::ptr::read_unaligned((entry + 4) as *const u64);
+					if address <= usize::MAX as u64 {
+						madt.local_apic_address = address as usize;
+					}
+				}
+				_ => {}
+			}
+			entry += length;
+		}
+	}
+	madt
+}
+
+/// ## Init ACPI
+/// Parse the MADT ("APIC" table) to discover the local and I/O APICs. \
+/// Must run before paging, the tables are read at their physical address.
+pub fn init(multiboot_info: usize) {
+	let madt = find_rsdt(multiboot_info)
+		.and_then(|rsdt| find_table(rsdt, b"APIC"))
+		.map(parse_madt);
+	*MADT.lock() = madt;
+}
@@ -0,0 +1,316 @@
+use crate::include::acpi::MADT;
+use crate::include::asm_utile::{cpuid, rdmsr, wrmsr};
+use crate::include::cmdline;
+use crate::include::interrupts::{InterruptIndex, PIC};
+use crate::include::pic::PIC_1_OFFSET;
+use crate::log;
+use crate::memory::ioremap::{ioremap, CacheMode};
+use crate::memory::physicalmemory::BITMAP;
+use crate::time::clock::{self, TickSource};
+use crate::time::pit;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+
+const CPUID_FEATURE_APIC: u32 = 1 << 9;
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers, offset from its MMIO base
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SVR: usize = 0xF0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+const LAPIC_SIZE: usize = 0x400;
+
+const SVR_ENABLE: u32 = 0x100;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_16: u32 = 0x3;
+const CALIBRATION_MS: u32 = 10;
+
+// I/O APIC registers, accessed through the select / window pair
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_SIZE: usize = 0x20;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+const MAX_IO_APICS: usize = 4;
+const ISA_IRQS: usize = 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LAPIC_BASE: AtomicUsize = AtomicUsize::new(0);
+static TIMER_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy)]
+struct IoApic {
+	base: usize,
+	gsi_base: u32,
+	redirections: u32,
+}
+
+impl IoApic {
+	unsafe fn read(&self, register: u32) -> u32 {
+		write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, register);
+		read_volatile((self.base + IOAPIC_WINDOW) as *const u32)
+	}
+
+	unsafe fn write(&self, register: u32, value: u32) {
+		write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, register);
+		write_volatile((self.base + IOAPIC_WINDOW) as *mut u32, value);
+	}
+
+	fn handles(&self, gsi: u32) -> bool {
+		self.gsi_base <= gsi && gsi < self.gsi_base + self.redirections
+	}
+
+	unsafe fn set_redirection(&self, gsi: u32, low: u32, destination: u8) {
+		let index = gsi - self.gsi_base;
+		self.write(
+			IOAPIC_REDIRECTION + index * 2 + 1,
+			(destination as u32) << 24,
+		);
+		self.write(IOAPIC_REDIRECTION + index * 2, low);
+	}
+
+	unsafe fn set_masked(&self, gsi: u32, masked: bool) {
+		let register = IOAPIC_REDIRECTION + (gsi - self.gsi_base) * 2;
+		let low = self.read(register);
+		if masked {
+			self.write(register, low | REDIRECTION_MASKED);
+		} else {
+			self.write(register, low & !REDIRECTION_MASKED);
+		}
+	}
+}
+
+struct IoApics {
+	list: [IoApic; MAX_IO_APICS],
+	count: usize,
+	// global system interrupt of each ISA IRQ after source overrides, u32::MAX if not routed
+	isa_gsi: [u32; ISA_IRQS],
+}
+
+impl IoApics {
+	fn find(&self, gsi: u32) -> Option<&IoApic> {
+		self.list[..self.count].iter().find(|io| io.handles(gsi))
+	}
+}
+
+static IO_APICS: Mutex<IoApics> = Mutex::new(IoApics {
+	list: [IoApic {
+		base: 0,
+		gsi_base: 0,
+		redirections: 0,
+	}; MAX_IO_APICS],
+	count: 0,
+	isa_gsi: [u32::MAX; ISA_IRQS],
+});
+
+fn lapic_read(register: usize) -> u32 {
+	unsafe { read_volatile((LAPIC_BASE.load(Ordering::Relaxed) + register) as *const u32) }
+}
+
+fn lapic_write(register: usize, value: u32) {
+	unsafe {
+		write_volatile(
+			(LAPIC_BASE.load(Ordering::Relaxed) + register) as *mut u32,
+			value,
+		)
+	}
+}
+
+/// True once interrupts are routed through the I/O APIC instead of the 8259.
+pub fn is_enabled() -> bool {
+	ENABLED.load(Ordering::Relaxed)
+}
+
+/// LAPIC timer input frequency in Hz (bus clock divided by 16), 0 if not calibrated.
+pub fn timer_frequency() -> u32 {
+	TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn end_of_interrupt() {
+	lapic_write(LAPIC_EOI, 0);
+}
+
+/// Mask or unmask an ISA IRQ (0-15) at its I/O APIC redirection entry.
+pub fn set_irq_mask(irq: u8, masked: bool) {
+	let io_apics = IO_APICS.lock();
+	let gsi = io_apics.isa_gsi[irq as usize];
+	if let Some(io_apic) = io_apics.find(gsi) {
+		unsafe { io_apic.set_masked(gsi, masked) };
+	}
+}
+
+/// Map registers uncached for good, return their base address.
+fn map_mmio(address: usize, size: usize) -> usize {
+	let frame = address & !0xFFF;
+	// device memory is never in the usable map, keep the allocator away from it anyway
+	let _ = BITMAP.lock().alloc_frame_address(frame);
+	ioremap(address as u64, size, CacheMode::Uncached)
+		.unwrap()
+		.base()
+}
+
+/// ## Calibrate_timer
+/// Count LAPIC timer ticks during a PIT channel 2 countdown. \
+/// Return the timer frequency in Hz with the divider set to 16.
+fn calibrate_timer() -> u32 {
+	lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+	lapic_write(
+		LAPIC_LVT_TIMER,
+		LVT_MASKED | InterruptIndex::ApicTimer as u32,
+	);
+	unsafe { pit::start_channel2(CALIBRATION_MS * 1000) };
+	lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+	while !unsafe { pit::channel2_expired() } {
+		core::hint::spin_loop();
+	}
+	let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT);
+	lapic_write(LAPIC_TIMER_INITIAL, 0);
+	elapsed * (1000 / CALIBRATION_MS)
+}
+
+/// LAPIC timer counts for one tick of ```clock```.
+pub fn timer_counts_per_tick() -> u32 {
+	timer_frequency() / clock::frequency()
+}
+
+/// Restart the LAPIC timer in periodic mode at `frequency` Hz.
+pub fn start_timer(frequency: u32) {
+	lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+	lapic_write(
+		LAPIC_LVT_TIMER,
+		LVT_TIMER_PERIODIC | InterruptIndex::ApicTimer as u32,
+	);
+	lapic_write(LAPIC_TIMER_INITIAL, timer_frequency() / frequency);
+}
+
+/// Fire the timer vector once after `count` LAPIC timer counts.
+pub fn start_timer_oneshot(count: u32) {
+	lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+	lapic_write(LAPIC_LVT_TIMER, InterruptIndex::ApicTimer as u32);
+	lapic_write(LAPIC_TIMER_INITIAL, count);
+}
+
+/// Remaining counts of the LAPIC timer, 0 once a one-shot expired.
+pub fn timer_current() -> u32 {
+	lapic_read(LAPIC_TIMER_CURRENT)
+}
+
+/// ## Init APIC
+/// Switch interrupt delivery from the 8259 to the local and I/O APICs when CPUID
+/// and the ACPI MADT report them, unless `apic=off` is on the command line. \
+/// ISA IRQs keep their vectors and their 8259 mask state. The LAPIC timer,
+/// calibrated against the PIT, becomes the tick source in place of the PIT. \
+/// Must run after paging and before the heap allocators take the free frames.
+pub fn init() {
+	if cmdline::option("apic") == Some("off") {
+		log!("APIC disabled by command line, using 8259 PIC");
+		return;
+	}
+	if cpuid(1).edx & CPUID_FEATURE_APIC == 0 {
+		log!("No APIC on this cpu, using 8259 PIC");
+		return;
+	}
+	let madt = MADT.lock();
+	let madt = match madt.as_ref() {
+		Some(madt) if madt.io_apic_count > 0 => madt,
+		_ => {
+			log!("No I/O APIC in ACPI MADT, using 8259 PIC");
+			return;
+		}
+	};
+
+	let lapic_base = map_mmio(madt.local_apic_address, LAPIC_SIZE);
+	unsafe { wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | APIC_BASE_ENABLE) };
+	LAPIC_BASE.store(lapic_base, Ordering::Relaxed);
+
+	lapic_write(LAPIC_TPR, 0);
+	lapic_write(LAPIC_LVT_LINT0, LVT_MASKED);
+	lapic_write(LAPIC_SVR, SVR_ENABLE | InterruptIndex::Spurious as u32);
+	let lapic_id = (lapic_read(LAPIC_ID) >> 24) as u8;
+
+	let [mask1, mask2] = unsafe {
+		let mut pic = PIC.lock();
+		let masks = pic.read_masks();
+		pic.disable();
+		masks
+	};
+	let pic_masks = (mask2 as u16) << 8 | mask1 as u16;
+
+	let mut io_apics = IO_APICS.lock();
+	for entry in madt.io_apics() {
+		let mut io_apic = IoApic {
+			base: map_mmio(entry.address as usize, IOAPIC_SIZE),
+			gsi_base: entry.gsi_base,
+			redirections: 0,
+		};
+		io_apic.redirections = unsafe { (io_apic.read(IOAPIC_VERSION) >> 16 & 0xFF) + 1 };
+		for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.redirections {
+			unsafe { io_apic.set_redirection(gsi, REDIRECTION_MASKED, lapic_id) };
+		}
+		let count = io_apics.count;
+		io_apics.list[count] = io_apic;
+		io_apics.count += 1;
+	}
+
+	for irq in 0..ISA_IRQS as u8 {
+		if irq == 2 {
+			// 8259 cascade, its GSI usually receives IRQ0 through an override
+			continue;
+		}
+		// ISA default: active high, edge triggered, identity mapped
+		let (gsi, flags) = madt
+			.overrides()
+			.iter()
+			.find(|o| o.source == irq)
+			.map_or((irq as u32, 0), |o| (o.gsi, o.flags));
+		let mut low = (PIC_1_OFFSET + irq) as u32;
+		if flags & 0x3 == 0x3 {
+			low |= REDIRECTION_ACTIVE_LOW;
+		}
+		if flags >> 2 & 0x3 == 0x3 {
+			low |= REDIRECTION_LEVEL;
+		}
+		if pic_masks & (1 << irq) != 0 {
+			low |= REDIRECTION_MASKED;
+		}
+		io_apics.isa_gsi[irq as usize] = gsi;
+		if let Some(io_apic) = io_apics.find(gsi) {
+			unsafe { io_apic.set_redirection(gsi, low, lapic_id) };
+		}
+	}
+	ENABLED.store(true, Ordering::Relaxed);
+
+	TIMER_FREQUENCY.store(calibrate_timer(), Ordering::Relaxed);
+	if clock::tick_source() == TickSource::Pit {
+		let gsi = io_apics.isa_gsi[0];
+		if let Some(io_apic) = io_apics.find(gsi) {
+			unsafe { io_apic.set_masked(gsi, true) };
+		}
+		// keep the rate chosen at boot for the PIT
+		let frequency = clock::frequency();
+		start_timer(frequency);
+		clock::set_tick_source(TickSource::Apic, frequency);
+	}
+	log!(
+		"APIC enabled: lapic id {} at {:#x}, {} I/O APIC, {} cpu, timer {} Hz",
+		lapic_id,
+		madt.local_apic_address,
+		io_apics.count,
+		madt.cpu_count,
+		timer_frequency()
+	);
+}
@@ -10,8 +10,79 @@ pub unsafe fn inb(port: u16) -> u8 {
 	value
 }
 
+#[allow(unused)]
+pub unsafe fn outw(port: u16, value: u16) {
+	asm!("out dx, ax", in("dx") port, in("ax") value);
+}
+
+#[allow(unused)]
+pub unsafe fn inw(port: u16) -> u16 {
+	let value: u16;
+	asm!("in ax, dx", out("ax") value, in("dx") port);
+	value
+}
+
+pub fn rdtsc() -> u64 {
+	let (low, high): (u32, u32);
+	unsafe {
+		asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+	}
+	(high as u64) << 32 | low as u64
+}
+
 pub fn hlt() {
 	unsafe {
 		asm!("hlt", options(nomem, nostack, preserves_flags));
 	}
 }
+
+/// Address of the last page fault.
+pub fn read_cr2() -> usize {
+	let address: usize;
+	unsafe {
+		asm!("mov {}, cr2", out(reg) address, options(nomem, nostack, preserves_flags));
+	}
+	address
+}
+
+#[allow(unused)]
+pub struct CpuidResult {
+	pub eax: u32,
+	pub ebx: u32,
+	pub ecx: u32,
+	pub edx: u32,
+}
+
+pub fn cpuid(leaf: u32) -> CpuidResult {
+	let (eax, ebx, ecx, edx);
+	unsafe {
+		// ebx may be reserved by LLVM, save it around cpuid
+		asm!(
+			"mov {tmp:e}, ebx",
+			"cpuid",
+			"xchg {tmp:e}, ebx",
+			tmp = out(reg) ebx,
+			inout("eax") leaf => eax,
+			inout("ecx") 0 => ecx,
+			out("edx") edx,
+			options(nostack, preserves_flags)
+		);
+	}
+	CpuidResult { eax, ebx, ecx, edx }
+}
+
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+	let (low, high): (u32, u32);
+	asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+	(high as u64) << 32 | low as u64
+}
+
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+	asm!(
+		"wrmsr",
+		in("ecx") msr,
+		in("eax") value as u32,
+		in("edx") (value >> 32) as u32,
+		options(nostack, preserves_flags)
+	);
+}
@@ -0,0 +1,41 @@
+use crate::include::multiboot;
+
+const CMDLINE_SIZE: usize = 256;
+const MULTIBOOT_TAG_CMDLINE: u32 = 1;
+
+static mut CMDLINE: [u8; CMDLINE_SIZE] = [0; CMDLINE_SIZE];
+static mut CMDLINE_LEN: usize = 0;
+
+/// ## Init command line
+/// Copy the Multiboot2 boot command line, the info structure is not kept mapped.
+pub fn init(multiboot_info: usize) {
+	let tag = match multiboot::parse_multiboot_info(multiboot_info, MULTIBOOT_TAG_CMDLINE) {
+		Some(tag) => tag,
+		None => return,
+	};
+	unsafe {
+		// tag type (u32), tag size (u32), then a null terminated string
+		let string = tag.add(8);
+		let mut len = 0;
+		while len < CMDLINE_SIZE && *string.add(len) != 0 {
+			CMDLINE[len] = *string.add(len);
+			len += 1;
+		}
+		CMDLINE_LEN = len;
+	}
+}
+
+pub fn get() -> &'static str {
+	unsafe {
+		let cmdline = &raw const CMDLINE;
+		core::str::from_utf8(&(&*cmdline)[..CMDLINE_LEN]).unwrap_or("")
+	}
+}
+
+/// Value of a `key=value` option, ex) `tick=rtc`.
+pub fn option(key: &str) -> Option<&'static str> {
+	get().split(' ').find_map(|word| {
+		word.strip_prefix(key)
+			.and_then(|rest| rest.strip_prefix('='))
+	})
+}
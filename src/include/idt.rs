@@ -1,9 +1,10 @@
 #[allow(unused_imports)]
 use crate::include::interrupts::{
-	alignement_check, bound_range_exceed, breakpoint, coproc_not_avail, coproc_segment_overrun,
-	div_by_zero, double_fault, floating_point_exception, general_protection_fault, inv_opcode,
-	inv_tss, keyboard_interrupt, machine_check, nmi, overflow, page_fault, segment_not_present,
-	simd_floating_point_exception, single_step_int, stack_segment_fault, syscall, timer_interrupt,
+	alignement_check, apic_timer_interrupt, bound_range_exceed, breakpoint, coproc_not_avail,
+	coproc_segment_overrun, div_by_zero, double_fault, floating_point_exception,
+	general_protection_fault, inv_opcode, inv_tss, keyboard_interrupt, machine_check, nmi,
+	overflow, page_fault, rtc_interrupt, segment_not_present, simd_floating_point_exception,
+	single_step_int, spurious_interrupt, stack_segment_fault, syscall, timer_interrupt,
 	virtualization_exception,
 };
 use core::arch::asm;
@@ -161,13 +162,25 @@ unsafe fn set_idt() {
 		idt_ptr.offset(0x21),
 		IdtEntry::new(keyboard_interrupt as usize, 0x08, 0x8E),
 	);
+	write_volatile(
+		idt_ptr.offset(0x28),
+		IdtEntry::new(rtc_interrupt as usize, 0x08, 0x8E),
+	);
 	// 0x20 ~ 0x27 : Hardware IRQs 0-7
-	// 0x70 ~ 0x77 : Hardware IRQs 8-15
+	// 0x28 ~ 0x2F : Hardware IRQs 8-15
+	write_volatile(
+		idt_ptr.offset(0x30),
+		IdtEntry::new(apic_timer_interrupt as usize, 0x08, 0x8E),
+	);
 	write_volatile(
 		idt_ptr.offset(0x80),
 		IdtEntry::new(syscall as usize, 0x08, 0xEE),
 	);
-	// 0x81 ~ 0xFF : User-Defined Interrupts
+	// 0x81 ~ 0xFE : User-Defined Interrupts
+	write_volatile(
+		idt_ptr.offset(0xFF),
+		IdtEntry::new(spurious_interrupt as usize, 0x08, 0x8E),
+	);
 }
 
 pub fn load() {
@@ -1,4 +1,8 @@
+use crate::include::apic;
+use crate::io::keyboard;
 use crate::io::shell::SHELL;
+use crate::time::clock::{self, TickSource};
+use crate::time::{idle, rtc, timer};
 
 #[repr(usize)]
 #[derive(Debug, PartialEq)]
@@ -28,13 +32,18 @@ pub enum InterruptIndex {
 	ControlProtectionException = 0x15,
 	Timer = PIC_1_OFFSET as usize,
 	Keyboard,
+	Rtc = PIC_2_OFFSET as usize,
+	ApicTimer = 0x30,
+	Spurious = 0xFF,
 }
 // source: https://en.wikipedia.org/wiki/Interrupt_descriptor_table
 
 use core::arch::asm;
 use spin::Mutex;
 
-use crate::include::asm_utile::{hlt, outb};
+use crate::include::asm_utile::{hlt, read_cr2};
+use crate::memory::demand;
+use crate::memory::virtualmemory;
 
 use super::pic::{ChainedPics, PIC_1_OFFSET, PIC_2_OFFSET};
 
@@ -91,6 +100,42 @@ macro_rules! create_isr_iretd {
     };
 }
 
+// Same with the error code pushed by the cpu, given to the handler and dropped before iretd.
+macro_rules! create_isr_error_iretd {
+    ($name:ident, $handler_fn:expr) => {
+        #[no_mangle]
+        pub extern "C" fn $name(_: IntStackFrame) {
+            unsafe {
+                core::arch::asm!(
+                    "push eax",
+                    "push ebx",
+                    "push ecx",
+                    "push edx",
+                    "push esi",
+                    "push edi",
+                    "push ebp",
+
+                    "push [esp + 28]",
+                    "call {handler}",
+                    "add esp, 4",
+
+                    "pop ebp",
+                    "pop edi",
+                    "pop esi",
+                    "pop edx",
+                    "pop ecx",
+                    "pop ebx",
+                    "pop eax",
+                    "add esp, 4",
+                    "iretd",
+                    handler = sym $handler_fn,
+                    options(noreturn),
+                );
+            }
+        }
+    };
+}
+
 #[derive(Debug)]
 #[allow(unused)]
 #[repr(C, packed)]
@@ -119,7 +164,6 @@ create_isr!(
 	general_protection_fault,
 	InterruptIndex::GeneralProtectionFault
 );
-create_isr!(page_fault, InterruptIndex::PageFault);
 // create_isr!(reserved, InterruptIndex::Reserved);
 create_isr!(
 	floating_point_exception,
@@ -139,42 +183,107 @@ create_isr!(
 // 	control_protection_exception,
 // 	InterruptIndex::ControlProtectionException
 // );
+create_isr_error_iretd!(page_fault, page_fault_handler);
 create_isr_iretd!(timer_interrupt, timer_interrupt_handler);
 create_isr_iretd!(keyboard_interrupt, keyboard_interrupt_handler);
+create_isr_iretd!(rtc_interrupt, rtc_interrupt_handler);
+create_isr_iretd!(apic_timer_interrupt, apic_timer_interrupt_handler);
+create_isr_iretd!(spurious_interrupt, spurious_interrupt_handler);
 
 pub static PIC: Mutex<ChainedPics> =
 	Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-const BASE_FREQUENCY: u32 = 1193182; // Base PIT frequency in Hz.
-#[allow(unused)]
-const DESIRED_FREQUENCY: u32 = 100; // Desired timer interrupt frequency in Hz.
-
-pub static mut TICKS: usize = 0;
+fn timer_interrupt_handler() {
+	if clock::tick_source() == TickSource::Pit {
+		clock::advance(idle::timer_interrupt());
+		timer::run_expired();
+	} else {
+		idle::exit();
+	}
+	end_of_interrupt(InterruptIndex::Timer);
+}
 
-pub unsafe fn configure_pit(frequency: u32) {
-	let divisor = BASE_FREQUENCY / frequency;
+fn rtc_interrupt_handler() {
+	idle::exit();
+	rtc::acknowledge();
+	if clock::tick_source() == TickSource::Rtc {
+		clock::tick();
+		timer::run_expired();
+	}
+	end_of_interrupt(InterruptIndex::Rtc);
+}
 
-	outb(0x43, 0x36);
-	outb(0x40, (divisor & 0xFF) as u8);
-	outb(0x40, (divisor >> 8) as u8);
+fn apic_timer_interrupt_handler() {
+	if clock::tick_source() == TickSource::Apic {
+		clock::advance(idle::timer_interrupt());
+		timer::run_expired();
+	} else {
+		idle::exit();
+	}
+	apic::end_of_interrupt();
 }
 
-fn timer_interrupt_handler() {
-	unsafe {
-		TICKS += 1;
-		PIC.lock()
-			.notify_end_of_interrupt(InterruptIndex::Timer as u8);
+/// ## Page_fault_handler
+/// A not present page in a lazy region gets a frame and the access is retried,
+/// any other fault stops the cpu.
+extern "C" fn page_fault_handler(error_code: u32) {
+	let address = read_cr2();
+	// bit 0: page present, bit 1: write access
+	let handled = match error_code & 0x3 {
+		0 | 2 => demand::handle_fault(address),
+		3 => virtualmemory::handle_cow_fault(address),
+		_ => false,
+	};
+	if handled {
+		return;
+	}
+	crate::println!("\x1b[4;mIDT: {:?}\x1b[15;m", InterruptIndex::PageFault);
+	crate::println!(
+		"\x1b[4;merror_code: {}, address: 0x{:08x}\x1b[15;m",
+		error_code,
+		address
+	);
+	loop {
+		hlt();
 	}
 }
 
+// The LAPIC doesn't expect an EOI for its spurious vector.
+fn spurious_interrupt_handler() {}
+
 static mut INPUT: &mut [u8; 77] = &mut [0u8; 77];
 static mut LEN: &mut usize = &mut 0;
 
+/// ## Keyboard_interrupt_handler
+/// Acknowledged first: a command may wait for the timer or the RTC,
+/// their interrupts must not stay behind this one. \
+/// A key pressed while a command runs is dropped.
 fn keyboard_interrupt_handler() {
-	unsafe {
-		SHELL.lock().read_input(INPUT, LEN);
-		PIC.lock()
-			.notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
+	idle::exit();
+	end_of_interrupt(InterruptIndex::Keyboard);
+	match SHELL.try_lock() {
+		Some(mut shell) => unsafe { shell.read_input(INPUT, LEN) },
+		None => {
+			keyboard::read(false);
+		}
+	}
+}
+
+/// Acknowledge a hardware interrupt to the LAPIC, or to the 8259 when the APIC is not used.
+pub fn end_of_interrupt(index: InterruptIndex) {
+	if apic::is_enabled() {
+		apic::end_of_interrupt();
+	} else {
+		unsafe { PIC.lock().notify_end_of_interrupt(index as u8) };
+	}
+}
+
+/// Mask or unmask an ISA IRQ line (0-15) on whichever controller routes it.
+pub fn set_irq_mask(irq: u8, masked: bool) {
+	if apic::is_enabled() {
+		apic::set_irq_mask(irq, masked);
+	} else {
+		unsafe { PIC.lock().set_mask(irq, masked) };
 	}
 }
 
@@ -198,3 +307,21 @@ pub fn is_enabled() -> bool {
 	}
 	(eflags & (1 << 9)) != 0
 }
+
+/// Run `f` with interrupts disabled, then restore the previous state. \
+/// Use it around locks also taken by an interrupt handler.
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+	F: FnOnce() -> R,
+{
+	let sti = is_enabled();
+
+	if sti {
+		unsafe { asm!("cli") };
+	}
+	let ret = f();
+	if sti {
+		unsafe { asm!("sti") };
+	}
+	ret
+}
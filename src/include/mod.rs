@@ -1,4 +1,7 @@
+pub mod acpi;
+pub mod apic;
 pub mod asm_utile;
+pub mod cmdline;
 pub mod gdt;
 pub mod idt;
 pub mod interrupts;
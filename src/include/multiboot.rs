@@ -50,6 +50,16 @@ pub struct MultibootMemoryMapEntry {
 	reserved: u32,
 }
 
+#[repr(C)]
+struct MultibootModuleTag {
+	_type: u32,
+	size: u32,
+	mod_start: u32,
+	mod_end: u32,
+}
+
+const MULTIBOOT_TAG_MODULE: u32 = 3;
+
 #[link_section = ".stack"]
 #[no_mangle]
 static mut STACK: [u8; 8192] = [0; 8192];
@@ -102,3 +112,27 @@ pub fn parse_multiboot_info(multiboot_info: usize, multiboot_tag: u32) -> Option
 
 	None
 }
+
+/// Size in bytes of the whole information structure.
+pub fn info_size(multiboot_info: usize) -> usize {
+	unsafe { (*(multiboot_info as *const MultibootInfo)).total_size as usize }
+}
+
+/// Call `f(start, end)` with the physical range of every boot module.
+pub fn for_each_module(multiboot_info: usize, mut f: impl FnMut(usize, usize)) {
+	let info = unsafe { &*(multiboot_info as *const MultibootInfo) };
+	let mut current_addr = multiboot_info + core::mem::size_of::<MultibootInfo>();
+	let end_addr = multiboot_info + info.total_size as usize;
+
+	while current_addr < end_addr {
+		let tag = unsafe { &*(current_addr as *const MultibootTag) };
+		if tag._type == 0 {
+			break;
+		}
+		if tag._type == MULTIBOOT_TAG_MODULE {
+			let module = unsafe { &*(current_addr as *const MultibootModuleTag) };
+			f(module.mod_start as usize, module.mod_end as usize);
+		}
+		current_addr = (current_addr + tag.size as usize + 7) & !7;
+	}
+}
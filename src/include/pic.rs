@@ -1,5 +1,6 @@
 use crate::include::asm_utile::{inb, outb};
 use crate::include::interrupts;
+use crate::time::pit;
 
 pub const PIC_1_OFFSET: u8 = 0x20;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -94,6 +95,22 @@ impl ChainedPics {
 		self.write_masks(u8::MAX, u8::MAX)
 	}
 
+	/// Mask or unmask a single IRQ line (0-15). \
+	/// Unmasking a slave line also unmasks the cascade line IRQ2.
+	pub unsafe fn set_mask(&mut self, irq: u8, masked: bool) {
+		let [mut mask1, mut mask2] = self.read_masks();
+		match (irq < 8, masked) {
+			(true, true) => mask1 |= 1 << irq,
+			(true, false) => mask1 &= !(1 << irq),
+			(false, true) => mask2 |= 1 << (irq - 8),
+			(false, false) => {
+				mask2 &= !(1 << (irq - 8));
+				mask1 &= !(1 << 2);
+			}
+		}
+		self.write_masks(mask1, mask2);
+	}
+
 	pub fn handles_interrupt(&self, interrupt_id: u8) -> bool {
 		self.pics.iter().any(|p| p.handles_interrupt(interrupt_id))
 	}
@@ -111,6 +128,6 @@ impl ChainedPics {
 pub fn load() {
 	unsafe {
 		interrupts::PIC.lock().initialize();
-		interrupts::configure_pit(100);
+		pit::configure_pit(pit::boot_frequency());
 	}
 }
@@ -0,0 +1,138 @@
+use crate::include::asm_utile::{inb, inw, outb, outw};
+use crate::io::block::{BlockError, SECTOR_SIZE};
+
+// Primary bus, the cdrom QEMU boots from is on the secondary one.
+const DATA: u16 = 0x1F0;
+const SECTOR_COUNT: u16 = 0x1F2;
+const LBA_LOW: u16 = 0x1F3;
+const LBA_MID: u16 = 0x1F4;
+const LBA_HIGH: u16 = 0x1F5;
+const DRIVE: u16 = 0x1F6;
+const STATUS: u16 = 0x1F7;
+const COMMAND: u16 = 0x1F7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+const READ_SECTORS: u8 = 0x20;
+const WRITE_SECTORS: u8 = 0x30;
+const CACHE_FLUSH: u8 = 0xE7;
+const IDENTIFY: u8 = 0xEC;
+
+// Status polls before a drive is given up, it answers in a few on QEMU.
+const TIMEOUT: usize = 1_000_000;
+// 28 bits LBA, the sector count register holds 0 for 256.
+const MAX_SECTORS: u64 = 1 << 28;
+
+/// ## AtaDrive
+/// Master drive of the primary bus in PIO mode, every word goes through
+/// the data port. \
+/// Polled, no interrupt is used.
+pub struct AtaDrive {
+	sectors: u64,
+}
+
+impl AtaDrive {
+	/// ## Probe
+	/// Identify the master drive of the primary bus. \
+	/// None without a drive or for a packet device like a cdrom.
+	pub fn probe() -> Option<AtaDrive> {
+		unsafe {
+			outb(DRIVE, 0xA0);
+			outb(SECTOR_COUNT, 0);
+			outb(LBA_LOW, 0);
+			outb(LBA_MID, 0);
+			outb(LBA_HIGH, 0);
+			outb(COMMAND, IDENTIFY);
+			// a floating bus reads 0xFF
+			if matches!(inb(STATUS), 0 | 0xFF) {
+				return None;
+			}
+			wait_not_busy().ok()?;
+			// ATAPI and SATA drives set a signature here
+			if inb(LBA_MID) != 0 || inb(LBA_HIGH) != 0 {
+				return None;
+			}
+			wait_data().ok()?;
+			let mut identify = [0u16; 256];
+			for word in identify.iter_mut() {
+				*word = inw(DATA);
+			}
+			// words 60 and 61, sectors reachable with 28 bits LBA
+			let sectors = (identify[61] as u64) << 16 | identify[60] as u64;
+			(sectors != 0).then_some(AtaDrive { sectors })
+		}
+	}
+
+	pub fn sectors(&self) -> u64 {
+		self.sectors
+	}
+
+	/// Select the drive and the `count` sectors from `sector`, then send `command`.
+	fn start(&self, command: u8, sector: u64, count: usize) -> Result<(), BlockError> {
+		if count == 0 || count > 256 || sector + count as u64 > self.sectors.min(MAX_SECTORS) {
+			return Err(BlockError::OutOfRange);
+		}
+		unsafe {
+			wait_not_busy()?;
+			outb(DRIVE, 0xE0 | ((sector >> 24) & 0xF) as u8);
+			outb(SECTOR_COUNT, count as u8);
+			outb(LBA_LOW, sector as u8);
+			outb(LBA_MID, (sector >> 8) as u8);
+			outb(LBA_HIGH, (sector >> 16) as u8);
+			outb(COMMAND, command);
+		}
+		Ok(())
+	}
+
+	pub fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+		self.start(READ_SECTORS, sector, buffer.len() / SECTOR_SIZE)?;
+		for chunk in buffer.chunks_exact_mut(SECTOR_SIZE) {
+			wait_data()?;
+			for word in chunk.chunks_exact_mut(2) {
+				word.copy_from_slice(&unsafe { inw(DATA) }.to_le_bytes());
+			}
+		}
+		Ok(())
+	}
+
+	pub fn write(&mut self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+		self.start(WRITE_SECTORS, sector, buffer.len() / SECTOR_SIZE)?;
+		for chunk in buffer.chunks_exact(SECTOR_SIZE) {
+			wait_data()?;
+			for word in chunk.chunks_exact(2) {
+				unsafe { outw(DATA, u16::from_le_bytes([word[0], word[1]])) };
+			}
+		}
+		unsafe { outb(COMMAND, CACHE_FLUSH) };
+		wait_not_busy()
+	}
+}
+
+fn wait_not_busy() -> Result<(), BlockError> {
+	for _ in 0..TIMEOUT {
+		if unsafe { inb(STATUS) } & STATUS_BSY == 0 {
+			return Ok(());
+		}
+	}
+	Err(BlockError::Timeout)
+}
+
+/// Wait until the drive has a sector to transfer.
+fn wait_data() -> Result<(), BlockError> {
+	for _ in 0..TIMEOUT {
+		let status = unsafe { inb(STATUS) };
+		if status & STATUS_BSY != 0 {
+			continue;
+		}
+		if status & (STATUS_ERR | STATUS_DF) != 0 {
+			return Err(BlockError::Io);
+		}
+		if status & STATUS_DRQ != 0 {
+			return Ok(());
+		}
+	}
+	Err(BlockError::Timeout)
+}
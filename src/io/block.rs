@@ -0,0 +1,114 @@
+use crate::io::ata::AtaDrive;
+use crate::memory::physicalmemory::BITMAP;
+use crate::memory::virtualmemory::with_frame;
+
+pub const SECTOR_SIZE: usize = 512;
+const SECTORS_PER_FRAME: u64 = (0x1000 / SECTOR_SIZE) as u64;
+
+#[derive(Debug)]
+pub enum BlockError {
+	OutOfRange,
+	OutOfMemory,
+	Timeout,
+	Io,
+}
+
+/// ## RamDisk
+/// Disk kept in contiguous frames taken out of the allocator, reached through
+/// ```with_frame``` so they need not be mapped.
+pub struct RamDisk {
+	start: u64,
+	frames: usize,
+}
+
+impl RamDisk {
+	/// Take `size` bytes of contiguous frames, Err if there is no such run.
+	pub fn new(size: usize) -> Result<RamDisk, BlockError> {
+		let frames = size / 0x1000;
+		let start = BITMAP
+			.lock()
+			.alloc_frames(frames, 0x1000, usize::MAX)
+			.map_err(|_| BlockError::OutOfMemory)?;
+		Ok(RamDisk {
+			start: start as u64,
+			frames,
+		})
+	}
+
+	pub fn sectors(&self) -> u64 {
+		self.frames as u64 * SECTORS_PER_FRAME
+	}
+
+	/// Call `f` with each sector of `[sector, sector + count)` in its frame.
+	fn for_each_sector(
+		&self,
+		sector: u64,
+		count: usize,
+		mut f: impl FnMut(usize, *mut u8),
+	) -> Result<(), BlockError> {
+		if sector + count as u64 > self.sectors() {
+			return Err(BlockError::OutOfRange);
+		}
+		for i in 0..count {
+			let current = sector + i as u64;
+			let frame = self.start + current / SECTORS_PER_FRAME * 0x1000;
+			let offset = (current % SECTORS_PER_FRAME) as usize * SECTOR_SIZE;
+			with_frame(frame, |page| f(i, unsafe { page.add(offset) }));
+		}
+		Ok(())
+	}
+
+	pub fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+		self.for_each_sector(sector, buffer.len() / SECTOR_SIZE, |i, data| {
+			let chunk = &mut buffer[i * SECTOR_SIZE..(i + 1) * SECTOR_SIZE];
+			unsafe { data.copy_to(chunk.as_mut_ptr(), SECTOR_SIZE) };
+		})
+	}
+
+	pub fn write(&mut self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+		self.for_each_sector(sector, buffer.len() / SECTOR_SIZE, |i, data| {
+			let chunk = &buffer[i * SECTOR_SIZE..(i + 1) * SECTOR_SIZE];
+			unsafe { data.copy_from(chunk.as_ptr(), SECTOR_SIZE) };
+		})
+	}
+}
+
+/// ## BlockDevice
+/// A disk read and written by whole sectors, the buffers are a multiple of
+/// ```SECTOR_SIZE```.
+pub enum BlockDevice {
+	Ram(RamDisk),
+	Ata(AtaDrive),
+}
+
+impl BlockDevice {
+	pub fn name(&self) -> &'static str {
+		match self {
+			BlockDevice::Ram(_) => "ram disk",
+			BlockDevice::Ata(_) => "ata disk",
+		}
+	}
+
+	pub fn sectors(&self) -> u64 {
+		match self {
+			BlockDevice::Ram(disk) => disk.sectors(),
+			BlockDevice::Ata(drive) => drive.sectors(),
+		}
+	}
+
+	pub fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+		assert!(buffer.len() % SECTOR_SIZE == 0, "partial sector");
+		match self {
+			BlockDevice::Ram(disk) => disk.read(sector, buffer),
+			BlockDevice::Ata(drive) => drive.read(sector, buffer),
+		}
+	}
+
+	pub fn write(&mut self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+		assert!(buffer.len() % SECTOR_SIZE == 0, "partial sector");
+		match self {
+			BlockDevice::Ram(disk) => disk.write(sector, buffer),
+			BlockDevice::Ata(drive) => drive.write(sector, buffer),
+		}
+	}
+}
@@ -8,6 +8,14 @@ const SHIFT_RIGHT: u8 = 0x36;
 const SHIFT_LEFT_RELEASE: u8 = 0x2A + 0x80;
 const SHIFT_RIGHT_RELEASE: u8 = 0x36 + 0x80;
 
+// Navigation keys, only returned while a command reads the keyboard.
+pub const KEY_UP: char = '\x11';
+pub const KEY_DOWN: char = '\x12';
+pub const KEY_LEFT: char = '\x13';
+pub const KEY_RIGHT: char = '\x14';
+pub const KEY_PAGE_UP: char = '\x15';
+pub const KEY_PAGE_DOWN: char = '\x16';
+
 static SHIFT_PRESSED: Mutex<bool> = Mutex::new(false);
 static mut LAST_SCANCODE: u8 = 0;
 pub static mut KEYMAP: Keymap = Keymap::EN;
@@ -49,6 +57,13 @@ pub fn read(processing: bool) -> Option<char> {
 		0x0E => {
 			return Some('\x7f');
 		}
+		// sent after 0xE0 by the arrow keys, alone by the keypad with num lock off
+		0x48 if processing => return Some(KEY_UP),
+		0x50 if processing => return Some(KEY_DOWN),
+		0x4B if processing => return Some(KEY_LEFT),
+		0x4D if processing => return Some(KEY_RIGHT),
+		0x49 if processing => return Some(KEY_PAGE_UP),
+		0x51 if processing => return Some(KEY_PAGE_DOWN),
 		0x3B | 0x3C => {
 			if scancode == 0x3B && !processing {
 				vga_buffer::switch(1);
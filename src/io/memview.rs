@@ -0,0 +1,332 @@
+use crate::io::keyboard;
+use crate::io::vga_buffer::{self, Color, BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::memory::dynamicmemory::{USER_HEAP_END, USER_HEAP_START};
+use crate::memory::physicalmemory::{BITMAP, HIGH_MEMORY_START, N_FRAMES};
+use crate::memory::region::{Region, RegionKind, REGIONS};
+use crate::memory::virtualmemory::{self, PAGE_DIRECTORY};
+use crate::println;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// First row is the title, the last two the legend and the cursor cell.
+const GRID_TOP: usize = 1;
+const GRID_ROWS: usize = BUFFER_HEIGHT - 3;
+const GRID_CELLS: usize = GRID_ROWS * BUFFER_WIDTH;
+
+const FULL_BLOCK: u8 = 0xDB;
+const DARK_SHADE: u8 = 0xB2;
+const MEDIUM_SHADE: u8 = 0xB1;
+const LIGHT_SHADE: u8 = 0xB0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum FrameState {
+	Free,
+	Kernel,
+	UserHeap,
+	PageTable,
+	Reserved,
+}
+
+const STATES: [FrameState; 5] = [
+	FrameState::Free,
+	FrameState::Kernel,
+	FrameState::UserHeap,
+	FrameState::PageTable,
+	FrameState::Reserved,
+];
+
+impl FrameState {
+	fn color(self) -> Color {
+		match self {
+			FrameState::Free => Color::Green,
+			FrameState::Kernel => Color::LightRed,
+			FrameState::UserHeap => Color::LightBlue,
+			FrameState::PageTable => Color::Yellow,
+			FrameState::Reserved => Color::DarkGray,
+		}
+	}
+
+	fn name(self) -> &'static str {
+		match self {
+			FrameState::Free => "free",
+			FrameState::Kernel => "kernel",
+			FrameState::UserHeap => "user heap",
+			FrameState::PageTable => "page tables",
+			FrameState::Reserved => "reserved",
+		}
+	}
+}
+
+/// ## Snapshot
+/// State of every frame under the end of usable memory below 4GB. \
+/// The bitmap only knows used or free, owners come from the page tables:
+/// frames mapped in the user heap and the tables themselves.
+/// Other used frames count as kernel.
+fn snapshot() -> Vec<FrameState> {
+	let regions: Vec<Region> = REGIONS.lock().regions().to_vec();
+	let usable = || {
+		regions
+			.iter()
+			.filter(|r| r.kind == RegionKind::Usable && r.start < HIGH_MEMORY_START)
+	};
+	let end = usable()
+		.map(|r| r.end.min(HIGH_MEMORY_START))
+		.max()
+		.unwrap_or(0);
+	let frames = (end.div_ceil(0x1000) as usize).min(N_FRAMES);
+	let mut states = vec![FrameState::Reserved; frames];
+
+	for region in usable() {
+		let start = (region.start / 0x1000) as usize;
+		let end = (region.end.div_ceil(0x1000) as usize).min(frames);
+		let bitmap = BITMAP.lock();
+		for (frame, state) in states.iter_mut().enumerate().take(end).skip(start) {
+			*state = if bitmap.is_frame_free(frame) {
+				FrameState::Free
+			} else {
+				FrameState::Kernel
+			};
+		}
+	}
+
+	let mut set = |address: u64, state: FrameState| {
+		if let Some(frame) = states.get_mut((address / 0x1000) as usize) {
+			*frame = state;
+		}
+	};
+	if virtualmemory::is_paging_enabled() {
+		let directory = PAGE_DIRECTORY.lock();
+		directory.for_each_mapping(USER_HEAP_START, USER_HEAP_END, |_, physical_address| {
+			set(physical_address, FrameState::UserHeap)
+		});
+		directory.for_each_page_table(|address| set(address, FrameState::PageTable));
+	} else {
+		// identity mapped heap
+		for address in (USER_HEAP_START..USER_HEAP_END).step_by(0x1000) {
+			if !BITMAP.lock().is_address_free(address) {
+				set(address as u64, FrameState::UserHeap);
+			}
+		}
+	}
+	states
+}
+
+struct View {
+	states: Vec<FrameState>,
+	frames_per_cell: usize,
+	// First frame of the top left cell, a multiple of the frames in a row.
+	first: usize,
+	cursor: usize,
+}
+
+impl View {
+	fn row_frames(&self) -> usize {
+		self.frames_per_cell * BUFFER_WIDTH
+	}
+
+	/// Zoom level where the whole map fits on the grid.
+	fn max_frames_per_cell(&self) -> usize {
+		self.states.len().div_ceil(GRID_CELLS).next_power_of_two()
+	}
+
+	fn max_first(&self) -> usize {
+		let row = self.row_frames();
+		let last_row = self.states.len().saturating_sub(1) / row * row;
+		last_row.saturating_sub((GRID_ROWS - 1) * row)
+	}
+
+	fn cell_start(&self, cell: usize) -> usize {
+		self.first + cell * self.frames_per_cell
+	}
+
+	fn counts(&self, cell: usize) -> [usize; STATES.len()] {
+		let mut counts = [0; STATES.len()];
+		let start = self.cell_start(cell);
+		let end = (start + self.frames_per_cell).min(self.states.len());
+		for state in &self.states[start.min(end)..end] {
+			counts[*state as usize] += 1;
+		}
+		counts
+	}
+
+	/// Keep the cursor on the grid and on a frame.
+	fn clamp(&mut self) {
+		self.first = self.first.min(self.max_first());
+		let last = (self.states.len().saturating_sub(1) - self.first) / self.frames_per_cell;
+		self.cursor = self.cursor.min(last).min(GRID_CELLS - 1);
+	}
+
+	fn scroll(&mut self, rows: isize) {
+		let row = self.row_frames() as isize;
+		self.first = (self.first as isize + rows * row).max(0) as usize;
+		self.clamp();
+	}
+
+	fn move_cursor(&mut self, rows: isize, columns: isize) {
+		let column = self.cursor % BUFFER_WIDTH;
+		let column = (column as isize + columns).clamp(0, BUFFER_WIDTH as isize - 1) as usize;
+		let mut row = (self.cursor / BUFFER_WIDTH) as isize + rows;
+		if row < 0 || row >= GRID_ROWS as isize {
+			let scroll = if row < 0 {
+				row
+			} else {
+				row - (GRID_ROWS as isize - 1)
+			};
+			let before = self.first;
+			self.scroll(scroll);
+			// pan the map, the cursor stays on the edge
+			row -= (self.first as isize - before as isize) / self.row_frames() as isize;
+			row = row.clamp(0, GRID_ROWS as isize - 1);
+		}
+		self.cursor = row as usize * BUFFER_WIDTH + column;
+		self.clamp();
+	}
+
+	/// ## Zoom
+	/// Change the frames per cell keeping the frame under the cursor at the same place
+	/// when possible.
+	fn zoom(&mut self, frames_per_cell: usize) {
+		let frames_per_cell = frames_per_cell.clamp(1, self.max_frames_per_cell());
+		let frame = self.cell_start(self.cursor);
+		let offset = self.cursor * frames_per_cell;
+		self.frames_per_cell = frames_per_cell;
+		let row = self.row_frames();
+		self.first = frame.saturating_sub(offset) / row * row;
+		self.first = self.first.min(self.max_first());
+		self.cursor = (frame - self.first) / frames_per_cell;
+		if self.cursor >= GRID_CELLS {
+			self.first = (frame / row).saturating_sub(GRID_ROWS - 1) * row;
+			self.cursor = (frame - self.first) / frames_per_cell;
+		}
+		self.clamp();
+	}
+
+	fn draw_cell(&self, cell: usize) {
+		let (x, y) = (cell % BUFFER_WIDTH, GRID_TOP + cell / BUFFER_WIDTH);
+		if self.cell_start(cell) >= self.states.len() {
+			vga_buffer::put_char(x, y, b' ', Color::Black, Color::Black);
+			return;
+		}
+		let counts = self.counts(cell);
+		let total: usize = counts.iter().sum();
+		let (index, &count) = counts
+			.iter()
+			.enumerate()
+			.max_by_key(|(_, &count)| count)
+			.unwrap();
+		let color = STATES[index].color();
+		if cell == self.cursor {
+			vga_buffer::put_char(x, y, b'+', Color::Black, color);
+			return;
+		}
+		// the shade tells how much of the cell is in the main state
+		let block = match count * 4 / total {
+			4 => FULL_BLOCK,
+			3 => DARK_SHADE,
+			2 => MEDIUM_SHADE,
+			_ => LIGHT_SHADE,
+		};
+		vga_buffer::put_char(x, y, block, color, Color::Black);
+	}
+
+	fn draw(&self) {
+		let end = self.cell_start(GRID_CELLS).min(self.states.len());
+		let title = format!(
+			"Memory 0x{:08x}-0x{:08x}, {} KiB per cell   arrows, pgup, pgdn, +, -, q",
+			self.first * 0x1000,
+			(end - 1) * 0x1000 + 0xFFF,
+			self.frames_per_cell * 4
+		);
+		line(0, &title, Color::White);
+		for cell in 0..GRID_CELLS {
+			self.draw_cell(cell);
+		}
+
+		line(BUFFER_HEIGHT - 2, "", Color::White);
+		let mut x = 0;
+		for state in STATES {
+			vga_buffer::put_char(
+				x,
+				BUFFER_HEIGHT - 2,
+				FULL_BLOCK,
+				state.color(),
+				Color::Black,
+			);
+			vga_buffer::put_str(
+				x + 2,
+				BUFFER_HEIGHT - 2,
+				state.name(),
+				Color::White,
+				Color::Black,
+			);
+			x += state.name().len() + 4;
+		}
+
+		let start = self.cell_start(self.cursor);
+		let end = (start + self.frames_per_cell).min(self.states.len());
+		let mut status = format!(
+			"0x{:08x}-0x{:08x}",
+			start * 0x1000,
+			(end - 1) * 0x1000 + 0xFFF
+		);
+		for (state, count) in STATES.iter().zip(self.counts(self.cursor)) {
+			if count != 0 {
+				status += &format!("  {} {}", state.name(), count);
+			}
+		}
+		line(BUFFER_HEIGHT - 1, &status, Color::White);
+	}
+}
+
+fn line(y: usize, s: &str, color: Color) {
+	vga_buffer::put_str(0, y, s, color, Color::Black);
+	for x in s.len()..BUFFER_WIDTH {
+		vga_buffer::put_char(x, y, b' ', color, Color::Black);
+	}
+}
+
+/// ## Memview
+/// Full-screen map of physical memory, one cell per range of frames colored by
+/// its main state. \
+/// Arrows move the cursor and pan at the edges, page up and down pan a screen,
+/// `+` or enter zoom in, `-` zooms out and `q` quits. \
+/// The map is a snapshot taken when the view opens.
+pub fn run() {
+	let states = snapshot();
+	if states.is_empty() {
+		println!("No usable memory under 4GB");
+		return;
+	}
+	let mut view = View {
+		states,
+		frames_per_cell: 1,
+		first: 0,
+		cursor: 0,
+	};
+	view.frames_per_cell = view.max_frames_per_cell();
+
+	vga_buffer::save_screen();
+	loop {
+		view.draw();
+		let input = loop {
+			if let Some(c) = keyboard::read(true) {
+				break c;
+			}
+		};
+		match input {
+			keyboard::KEY_UP => view.move_cursor(-1, 0),
+			keyboard::KEY_DOWN => view.move_cursor(1, 0),
+			keyboard::KEY_LEFT => view.move_cursor(0, -1),
+			keyboard::KEY_RIGHT => view.move_cursor(0, 1),
+			keyboard::KEY_PAGE_UP => view.scroll(-(GRID_ROWS as isize)),
+			keyboard::KEY_PAGE_DOWN => view.scroll(GRID_ROWS as isize),
+			'+' | '=' | '\n' => view.zoom(view.frames_per_cell / 2),
+			'-' => view.zoom(view.frames_per_cell * 2),
+			'q' => break,
+			_ => {}
+		}
+	}
+	vga_buffer::restore_screen();
+}
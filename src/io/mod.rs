@@ -1,5 +1,11 @@
+#[cfg(feature = "swap")]
+pub mod ata;
+#[cfg(feature = "swap")]
+pub mod block;
 pub mod hexdump;
 pub mod keyboard;
+pub mod memview;
 pub mod println;
 pub mod shell;
+pub mod speaker;
 pub mod vga_buffer;
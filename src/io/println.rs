@@ -13,6 +13,12 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// println with a wall clock stamp, for kernel messages.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => ($crate::io::println::_log(format_args!($($arg)*)));
+}
+
 use core::{arch::asm, fmt};
 
 #[doc(hidden)]
@@ -36,3 +42,21 @@ pub fn _print(args: fmt::Arguments) {
 		}
 	}
 }
+
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+	use crate::time::{clock, rtc};
+
+	match rtc::now() {
+		Some(now) => _print(format_args!("[{}] {}\n", now, args)),
+		None => {
+			let uptime = clock::uptime();
+			_print(format_args!(
+				"[{:>5}.{:03}] {}\n",
+				uptime.as_secs(),
+				uptime.subsec_millis(),
+				args
+			))
+		}
+	}
+}
@@ -1,7 +1,22 @@
+use crate::include::apic;
 use crate::io::hexdump;
 use crate::io::keyboard;
+use crate::io::memview;
+use crate::io::speaker;
 use crate::io::vga_buffer::WRITER;
-use crate::memory::physicalmemory::BITMAP;
+use crate::memory::demand::LAZY_REGIONS;
+use crate::memory::dynamicmemory::{KERNEL_ALLOCATOR, USER_ALLOCATOR};
+use crate::memory::heap_test;
+use crate::memory::pageflags::PageFlags;
+use crate::memory::pagewalk;
+use crate::memory::physicalmemory::{self, BITMAP};
+use crate::memory::region::REGIONS;
+#[cfg(feature = "swap")]
+use crate::memory::swap::SWAP;
+use crate::memory::virtualmemory;
+use crate::memory::vmalloc::VMALLOC;
+use crate::memory::vrange::VirtualRanges;
+use crate::time::{clock, idle, rtc, tsc};
 use crate::{print, println};
 use spin::Mutex;
 
@@ -40,11 +55,25 @@ impl Shell {
 			Ok("halt") => self.halt(),
 			Ok("bitmap") => self.bitmap(false),
 			Ok("bitmap --all") => self.bitmap(true),
+			Ok("meminfo") => self.meminfo(),
+			Ok("memmap") => self.memmap(),
+			Ok("memview") => memview::run(),
+			Ok("vmmap") => self.vmmap(),
+			Ok(command) if command.starts_with("translate ") => self.translate(command),
+			Ok("framebench") => heap_test::frame_alloc_bench(),
+			Ok("cowtest") => heap_test::cow_test(),
+			Ok("swaptest") => heap_test::swap_test(),
+			Ok("shmtest") => heap_test::shm_test(),
 			Ok("keymap") => self.keymap(),
 			Ok("help") => self.help(),
 			Ok("uptime") => self.uptime(),
+			Ok("clock") => self.clock(),
+			Ok(command) if command == "date" || command.starts_with("date ") => self.date(command),
 			Ok("panic") => self.panic(),
 			Ok(command) if command.starts_with("interrupt ") => self.interrupt(command),
+			Ok(command) if command.starts_with("sleep ") => self.sleep(command),
+			Ok(command) if command.starts_with("beep ") => self.beep(command),
+			Ok(command) if command == "play" || command.starts_with("play ") => self.play(command),
 			Ok(command) => print!("Command not found: {}\n", command),
 			Err(_) => print!("Command not UTF-8 input\n"),
 		}
@@ -57,9 +86,21 @@ For print some information of kernel :
    stack        visualy see stack status with hex and char
    bitmap       visualy see allocated physical frame
    bitmap --all   visualy see all physical frame
+   meminfo      physical memory statistics
+   memmap       firmware memory map and reserved ranges
+   memview      full-screen physical memory map, arrows to move, + - to zoom
+   vmmap        virtual ranges of the active space with their frames and flags
+   translate <vaddr>   page directory and table entries of a hex address
+   framebench   measure physical frame search latency
+   cowtest      check a cloned address space shares frames until a write
+   swaptest     touch more memory than is free, pages go through the swap
+   shmtest      map a shared memory object in two spaces and a clone
 
 Os management :
    interrupt <0-255>    make system interrupt
+   sleep <ms>           wait for given milliseconds
+   beep <freq> <ms>     play a tone on the pc speaker
+   play [note:ms ...]   play a melody, notes like A4, C#5, 440 or - for a rest
    halt                 stop cpu
    reboot               reboot the kernel
 
@@ -67,6 +108,8 @@ User experience :
    keymap       change keyboard layout
    help         presenting the commands in our kernel
    uptime       show uptime this os
+   clock        show timer sources and calibrated frequencies
+   date [YYYY-MM-DD HH:MM:SS]   show or set the real-time clock
 "
 		)
 	}
@@ -76,12 +119,101 @@ User experience :
 	}
 
 	fn uptime(&self) {
+		let uptime = clock::uptime();
 		println!(
-			"KFS os running while {} seconds.",
-			unsafe { crate::include::interrupts::TICKS } / 100
+			"KFS os running while {}.{:03} seconds.",
+			uptime.as_secs(),
+			uptime.subsec_millis()
 		);
 	}
 
+	fn clock(&self) {
+		println!(
+			"Tick source: {:?} at {} Hz, tickless idle: {}",
+			clock::tick_source(),
+			clock::frequency(),
+			if idle::is_tickless() { "on" } else { "off" }
+		);
+		match tsc::get() {
+			Some(tsc) => println!(
+				"TSC: {}.{:03} MHz{}",
+				tsc.frequency / 1_000_000,
+				tsc.frequency / 1_000 % 1_000,
+				if tsc.invariant { ", invariant" } else { "" }
+			),
+			None => println!("TSC: not available"),
+		}
+		if apic::is_enabled() {
+			println!("LAPIC timer: {} Hz", apic::timer_frequency());
+		}
+		println!("now: {} ns", tsc::now_ns());
+	}
+
+	fn date(&self, input: &str) {
+		match input.strip_prefix("date ") {
+			None => println!("{}", rtc::read()),
+			Some(datetime) => match rtc::DateTime::parse(datetime) {
+				Ok(datetime) => {
+					if let Err(e) = rtc::write(&datetime) {
+						println!("Can not set date: {:?}", e);
+					}
+				}
+				Err(e) => println!("Invalid date {:?}, expected YYYY-MM-DD HH:MM:SS", e),
+			},
+		}
+	}
+
+	fn sleep(&self, input: &str) {
+		if let Some(ms) = input.strip_prefix("sleep ") {
+			match ms.parse::<u64>() {
+				Ok(ms) => clock::sleep_ms(ms),
+				Err(_) => println!("Invalid milliseconds: {}", ms),
+			}
+		}
+	}
+
+	fn beep(&self, input: &str) {
+		let mut args = input.split_whitespace().skip(1);
+		let frequency = args.next().and_then(|f| f.parse::<u32>().ok());
+		let ms = args.next().and_then(|ms| ms.parse::<u64>().ok());
+		match (frequency, ms, args.next()) {
+			(Some(frequency), Some(ms), None) => {
+				if let Err(e) = speaker::beep(frequency, ms) {
+					println!("Can not beep: {:?}", e);
+				}
+			}
+			_ => println!("Invalid input. Please use the format 'beep <freq> <ms>'."),
+		}
+	}
+
+	fn play(&self, input: &str) {
+		let mut notes = [speaker::Note {
+			frequency: 0,
+			ms: 0,
+		}; INPUT_SIZE / 2];
+		let mut count = 0;
+		for note in input.split_whitespace().skip(1) {
+			match speaker::parse_note(note) {
+				Ok(parsed) => {
+					notes[count] = parsed;
+					count += 1;
+				}
+				Err(e) => {
+					println!("{:?}: {}", e, note);
+					return;
+				}
+			}
+		}
+		let melody = if count == 0 {
+			&speaker::DEFAULT_MELODY[..]
+		} else {
+			&notes[..count]
+		};
+		if let Err(e) = speaker::play_melody(melody) {
+			println!("Can not play: {:?}", e);
+		}
+	}
+
 	fn keymap(&self) {
 		let keymap = unsafe { keyboard::KEYMAP } as usize;
 		println!(
@@ -110,6 +242,175 @@ User experience :
 		}
 	}
 
+	fn meminfo(&self) {
+		let stats = physicalmemory::stats();
+		let (user_used, user_free) = {
+			let user = USER_ALLOCATOR.lock();
+			(user.used_pages(), user.free_pages())
+		};
+		let (kernel_used, kernel_free) = {
+			let kernel = KERNEL_ALLOCATOR.lock();
+			(kernel.used_pages(), kernel.free_pages())
+		};
+		let lazy_used = LAZY_REGIONS.lock().committed();
+		let other = stats.used
+			- stats.reserved
+			- stats.kernel
+			- stats.page_tables
+			- user_used
+			- kernel_used
+			- lazy_used
+			// the heaps keep the frames of their free blocks
+			- user_free
+			- kernel_free;
+
+		println!("{:<14}{:>10}{:>12}", "", "frames", "KiB");
+		let row =
+			|name: &str, frames: usize| println!("{:<14}{:>10}{:>12}", name, frames, frames * 4);
+		row("total", stats.total);
+		row("usable", stats.usable);
+		row("reserved", stats.reserved);
+		row("kernel image", stats.kernel);
+		row("page tables", stats.page_tables);
+		row("user heap", user_used);
+		row("kernel heap", kernel_used);
+		row("lazy heap", lazy_used);
+		row("other", other);
+		row(
+			"free",
+			stats.usable.saturating_sub(stats.used - stats.reserved),
+		);
+		row("free >4GB", stats.high);
+		println!(
+			"heap free lists: user {} KiB, kernel {} KiB",
+			user_free * 4,
+			kernel_free * 4
+		);
+		let space =
+			|ranges: &VirtualRanges| (ranges.free_size() / 1024, ranges.largest_free() / 1024);
+		let spaces = [
+			("user heap", space(USER_ALLOCATOR.lock().ranges())),
+			("kernel heap", space(KERNEL_ALLOCATOR.lock().ranges())),
+			("vmalloc", space(&VMALLOC.lock())),
+		];
+		for (name, (free, largest)) in spaces {
+			println!(
+				"{:<12} virtual {:>10} KiB free, largest {:>10} KiB",
+				name, free, largest
+			);
+		}
+		#[cfg(feature = "swap")]
+		{
+			let swap = SWAP.lock().stats();
+			println!(
+				"swap {}: {} KiB used of {} KiB, {} pages out, {} in",
+				swap.device,
+				swap.used * 4,
+				swap.slots * 4,
+				swap.swapped_out,
+				swap.swapped_in
+			);
+		}
+		for region in LAZY_REGIONS.lock().regions() {
+			println!(
+				"lazy 0x{:08x}-0x{:08x} {:>10} KiB reserved {:>10} KiB committed",
+				region.start,
+				region.end - 1,
+				region.pages() * 4,
+				region.committed * 4
+			);
+		}
+	}
+
+	fn memmap(&self) {
+		let map = REGIONS.lock();
+		for region in map.regions() {
+			println!(
+				"0x{:012x}-0x{:012x} {:<16?}{:>10} KiB",
+				region.start,
+				region.end - 1,
+				region.kind,
+				(region.end - region.start) / 1024
+			);
+		}
+		println!("Reserved:");
+		for reservation in map.reservations() {
+			println!(
+				"0x{:08x}-0x{:08x} {:<20}{:>10} KiB",
+				reservation.start,
+				reservation.end - 1,
+				reservation.name,
+				(reservation.end - reservation.start) / 1024
+			);
+		}
+	}
+
+	fn vmmap(&self) {
+		if !virtualmemory::is_paging_enabled() {
+			println!("Paging is disabled, virtual addresses are physical ones.");
+			return;
+		}
+		for mapping in pagewalk::mappings() {
+			let flags = mapping.flags;
+			let bit = |flag: PageFlags, set: char| if flags.contains(flag) { set } else { '-' };
+			let mut others = flags;
+			others.remove(
+				PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER | PageFlags::NO_EXECUTE,
+			);
+			print!(
+				"{:08x}-{:08x} r{}{}{} {:012x} {:>8}K",
+				mapping.start,
+				mapping.end - 1,
+				bit(PageFlags::WRITABLE, 'w'),
+				if flags.contains(PageFlags::NO_EXECUTE) {
+					'-'
+				} else {
+					'x'
+				},
+				bit(PageFlags::USER, 'u'),
+				mapping.physical_address,
+				(mapping.end - mapping.start) / 1024
+			);
+			if others == PageFlags::empty() {
+				println!();
+			} else {
+				println!(" {:?}", others);
+			}
+		}
+	}
+
+	fn translate(&self, input: &str) {
+		let Some(address) = input.strip_prefix("translate ") else {
+			return;
+		};
+		let digits = address.trim().trim_start_matches("0x");
+		let Ok(virtual_address) = usize::from_str_radix(digits, 16) else {
+			println!("Invalid address: {}, expected hex like 0xC0000000", address);
+			return;
+		};
+		if !virtualmemory::is_paging_enabled() {
+			println!("Paging is disabled, 0x{:x} is physical.", virtual_address);
+			return;
+		}
+		let translation = pagewalk::translate(virtual_address);
+		let entry = translation.directory_entry;
+		println!("PDE 0x{:016x} {:?}", entry, PageFlags::from_entry(entry));
+		match translation.table_entry {
+			Some(entry) => println!("PTE 0x{:016x} {:?}", entry, PageFlags::from_entry(entry)),
+			None if entry & 0x1 == 0 => println!("PTE none, no page table"),
+			None => println!("PTE none, large page"),
+		}
+		match translation.page {
+			Some((frame, flags)) => println!(
+				"0x{:08x} -> 0x{:x} {:?}",
+				virtual_address,
+				frame + (virtual_address & 0xFFF) as u64,
+				flags
+			),
+			None => println!("0x{:08x} not mapped", virtual_address),
+		}
+	}
+
 	fn bitmap(&mut self, all_flag: bool) {
 		let mut line_count = 0;
 
@@ -0,0 +1,155 @@
+use crate::time::clock::{self, Duration};
+use crate::time::{pit, timer};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const MIN_FREQUENCY: u32 = 20; // channel 2 divisor must fit in 16 bits
+const MAX_FREQUENCY: u32 = 20_000;
+
+const BELL_FREQUENCY: u32 = 880;
+const BELL_DURATION: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum SpeakerError {
+	InvalidFrequency,
+	InvalidNote,
+}
+
+/// A tone of `frequency` Hz for `ms` milliseconds, a frequency of 0 is a rest.
+#[derive(Clone, Copy)]
+pub struct Note {
+	pub frequency: u32,
+	pub ms: u32,
+}
+
+// Bumped on every new tone so a pending bell stop doesn't cut a later one.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+fn play(frequency: u32) -> usize {
+	unsafe { pit::start_tone(frequency) };
+	GENERATION.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+fn stop() {
+	unsafe { pit::stop_tone() };
+}
+
+fn stop_callback(generation: usize) {
+	if GENERATION.load(Ordering::Relaxed) == generation {
+		stop();
+	}
+}
+
+/// ## Beep
+/// Play `frequency` Hz for `ms` milliseconds and wait until it's done.
+pub fn beep(frequency: u32, ms: u64) -> Result<(), SpeakerError> {
+	if !(MIN_FREQUENCY..=MAX_FREQUENCY).contains(&frequency) {
+		return Err(SpeakerError::InvalidFrequency);
+	}
+	play(frequency);
+	clock::sleep_ms(ms);
+	stop();
+	Ok(())
+}
+
+/// ## Bell
+/// Short beep for the BEL character. \
+/// Doesn't wait: the speaker is stopped from a timer, so it can be rung with the
+/// VGA writer locked or from an interrupt handler.
+pub fn bell() {
+	let generation = play(BELL_FREQUENCY);
+	if timer::add_oneshot(BELL_DURATION, stop_callback, generation).is_err() {
+		stop();
+	}
+}
+
+pub fn play_melody(notes: &[Note]) -> Result<(), SpeakerError> {
+	for note in notes {
+		match note.frequency {
+			0 => clock::sleep_ms(note.ms as u64),
+			frequency => beep(frequency, note.ms as u64)?,
+		}
+	}
+	Ok(())
+}
+
+/// Frequency in Hz of a note name like `A4`, `C#5` or `Bb3`.
+fn note_frequency(name: &str) -> Option<u32> {
+	// C0 in millihertz, then each semitone is a factor 2^(1/12) (x1000)
+	const C0: u64 = 16_352;
+	const SEMITONES: [u64; 12] = [
+		1000, 1059, 1122, 1189, 1260, 1335, 1414, 1498, 1587, 1682, 1782, 1888,
+	];
+
+	let semitone: usize = match name.as_bytes().first()? {
+		b'C' => 0,
+		b'D' => 2,
+		b'E' => 4,
+		b'F' => 5,
+		b'G' => 7,
+		b'A' => 9,
+		b'B' => 11,
+		_ => return None,
+	};
+	let (semitone, octave) = match name.as_bytes().get(1)? {
+		b'#' => (semitone + 1, &name[2..]),
+		b'b' => (semitone.checked_sub(1)?, &name[2..]),
+		_ => (semitone, &name[1..]),
+	};
+	let index = octave.parse::<usize>().ok()? * 12 + semitone;
+	if index >= 12 * 10 {
+		return None;
+	}
+	Some((C0 * (1 << (index / 12)) * SEMITONES[index % 12] / 1_000_000) as u32)
+}
+
+/// ## Parse_note
+/// Parse `<note>:<ms>` where note is a name like `A4`, `C#5`, `Bb3`,
+/// a frequency in Hz or `-` for a rest.
+pub fn parse_note(input: &str) -> Result<Note, SpeakerError> {
+	let (name, ms) = input.split_once(':').ok_or(SpeakerError::InvalidNote)?;
+	let ms = ms.parse::<u32>().map_err(|_| SpeakerError::InvalidNote)?;
+	let frequency = match name {
+		"-" => 0,
+		name => match name.parse::<u32>() {
+			Ok(frequency) => frequency,
+			Err(_) => note_frequency(name).ok_or(SpeakerError::InvalidNote)?,
+		},
+	};
+	Ok(Note { frequency, ms })
+}
+
+/// Played by `play` without argument.
+pub const DEFAULT_MELODY: [Note; 8] = [
+	Note {
+		frequency: 262,
+		ms: 200,
+	},
+	Note {
+		frequency: 294,
+		ms: 200,
+	},
+	Note {
+		frequency: 330,
+		ms: 200,
+	},
+	Note {
+		frequency: 349,
+		ms: 200,
+	},
+	Note {
+		frequency: 392,
+		ms: 400,
+	},
+	Note {
+		frequency: 0,
+		ms: 100,
+	},
+	Note {
+		frequency: 392,
+		ms: 200,
+	},
+	Note {
+		frequency: 523,
+		ms: 600,
+	},
+];
@@ -65,8 +65,8 @@ struct ScreenChar {
 	color_code: ColorCode,
 }
 
-const BUFFER_WIDTH: usize = 80;
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
 
 use volatile::Volatile;
 
@@ -146,6 +146,7 @@ impl Writer {
 					self.write_byte(byte);
 				}
 				0x7f => self.write_byte(0x7f),
+				0x07 => speaker::bell(),
 				_ => {
 					self.write_byte(0xfe);
 				}
@@ -269,6 +270,11 @@ static mut VGA2_BUFFER: [ScreenChar; BUFFER_WIDTH * BUFFER_HEIGHT] = [ScreenChar
 };
 	BUFFER_WIDTH * BUFFER_HEIGHT];
 static mut CURRENT_VGA: u8 = 1;
+static mut SAVED_SCREEN: [ScreenChar; BUFFER_WIDTH * BUFFER_HEIGHT] = [ScreenChar {
+	ascii_character: b' ',
+	color_code: ColorCode(0),
+};
+	BUFFER_WIDTH * BUFFER_HEIGHT];
 
 pub fn switch(new_vga: u8) {
 	unsafe {
@@ -290,6 +296,34 @@ pub fn switch(new_vga: u8) {
 	}
 }
 
+/// Keep the screen before a full-screen view draws over it.
+pub fn save_screen() {
+	save_vga(&raw mut SAVED_SCREEN);
+}
+
+pub fn restore_screen() {
+	load_vga(&raw const SAVED_SCREEN);
+}
+
+/// Write `character` at column `x` of row `y`, without moving the writer.
+pub fn put_char(x: usize, y: usize, character: u8, foreground: Color, background: Color) {
+	write_screen_char_at(
+		ScreenChar {
+			ascii_character: character,
+			color_code: ColorCode::new(foreground, background),
+		},
+		x,
+		y,
+	);
+}
+
+/// Same as ```put_char``` for a line starting at `x`, cut at the end of the row.
+pub fn put_str(x: usize, y: usize, s: &str, foreground: Color, background: Color) {
+	for (i, byte) in s.bytes().take(BUFFER_WIDTH.saturating_sub(x)).enumerate() {
+		put_char(x + i, y, byte, foreground, background);
+	}
+}
+
 fn save_vga(buffer: *mut [ScreenChar; BUFFER_WIDTH * BUFFER_HEIGHT]) {
 	unsafe {
 		for y in 0..BUFFER_HEIGHT {
@@ -327,6 +361,7 @@ fn write_screen_char_at(screen_char: ScreenChar, x: usize, y: usize) {
 }
 
 use crate::include::asm_utile;
+use crate::io::speaker;
 
 fn set_cursor(x: usize) {
 	let position = (BUFFER_HEIGHT - 1) * BUFFER_WIDTH + x;
@@ -7,11 +7,11 @@ extern crate alloc;
 mod include;
 mod io;
 mod memory;
+mod time;
 
 #[allow(unused_imports)]
 use core::arch::asm;
 
-use include::asm_utile::hlt;
 use io::shell::SHELL;
 use memory::dynamicmemory::Privilege;
 
@@ -29,23 +29,44 @@ fn welcome_message() {
 
 #[allow(unused)]
 fn init(multiboot_info: usize, paging_status: bool) {
+	include::cmdline::init(multiboot_info);
 	include::gdt::load();
 	include::idt::load();
 	include::pic::load();
+	time::rtc::init();
+	time::tsc::init();
+	include::acpi::init(multiboot_info);
 	memory::physicalmemory::init(multiboot_info);
+	// the MADT is copied, nothing reads the ACPI tables anymore
+	memory::region::REGIONS.lock().reclaim_acpi();
 	memory::virtualmemory::init(multiboot_info, paging_status);
+	include::apic::init();
+	time::idle::init();
+	log!(
+		"Interrupts ready, tick source: {:?} at {} Hz, tickless idle: {}",
+		time::clock::tick_source(),
+		time::clock::frequency(),
+		time::idle::is_tickless()
+	);
+	#[cfg(feature = "swap")]
+	memory::swap::init();
 	memory::dynamicmemory::USER_ALLOCATOR.lock().init(
-		0x300000,
-		0x800B_5000, // ≒ 2GB
+		memory::dynamicmemory::USER_HEAP_START,
+		memory::dynamicmemory::USER_HEAP_END,
 		Privilege::User,
 		paging_status,
 	);
 	memory::dynamicmemory::KERNEL_ALLOCATOR.lock().init(
-		0x800B_6000,
-		0xBFFE_0000, // ≒ 1GB
+		memory::dynamicmemory::KERNEL_HEAP_START,
+		memory::dynamicmemory::KERNEL_HEAP_END,
 		Privilege::Kernel,
 		paging_status,
 	);
+	log!(
+		"Memory ready, paging: {}, pae: {}",
+		paging_status,
+		memory::pae::is_enabled()
+	);
 }
 
 #[no_mangle]
@@ -59,6 +80,6 @@ pub extern "C" fn kernel_main(magic: u32, multiboot_info: usize) {
 	SHELL.lock().display_prompt();
 	unsafe { asm!("sti") };
 	loop {
-		hlt();
+		time::idle::idle();
 	}
 }
@@ -0,0 +1,579 @@
+use crate::memory::pae::{self, PAE_DIRECTORY};
+use crate::memory::pageflags::PageFlags;
+use crate::memory::physicalmemory::{self, PhysicalMemoryError, BITMAP, HIGH_MEMORY_START};
+#[cfg(feature = "swap")]
+use crate::memory::swap;
+use crate::memory::virtualmemory::{
+	flush_all, with_frame, VirtualMemoryError, PAGE_DIRECTORY, PDA,
+};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::iter;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+// Range with page tables of its own in each space, the rest is shared with the kernel.
+// Between the user and the kernel heaps, their pages are the same in every space.
+pub const USER_START: usize = 0x4000_0000;
+pub const USER_END: usize = 0x8000_0000;
+
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const MAX_SPACES: usize = 64;
+
+// Physical address of the directory, or the PDPT with PAE, loaded in CR3.
+static KERNEL_ROOT: AtomicUsize = AtomicUsize::new(0);
+static ACTIVE_ROOT: AtomicUsize = AtomicUsize::new(0);
+// Roots of the other spaces alive, 0 for a free slot.
+// A fixed array, the heap maps pages while this is locked.
+static SPACES: Mutex<[usize; MAX_SPACES]> = Mutex::new([0; MAX_SPACES]);
+
+fn entries() -> usize {
+	if pae::is_enabled() {
+		512
+	} else {
+		1024
+	}
+}
+
+/// A slot is a directory entry, counted across the 4 PAE directories.
+fn slot_shift() -> usize {
+	if pae::is_enabled() {
+		21
+	} else {
+		22
+	}
+}
+
+/// First recursive slot, up to the last one.
+fn recursive_slot() -> usize {
+	if pae::is_enabled() {
+		2048 - 4
+	} else {
+		1023
+	}
+}
+
+fn is_private(slot: usize) -> bool {
+	let address = slot << slot_shift();
+	(USER_START..USER_END).contains(&address)
+}
+
+fn is_shared(slot: usize) -> bool {
+	!is_private(slot) && slot < recursive_slot()
+}
+
+/// Read entry `index` of the table at physical address `table`.
+fn read_entry(table: usize, index: usize) -> u64 {
+	with_frame((table & !0xFFF) as u64, |frame| unsafe {
+		let base = frame.add(table & 0xFFF);
+		if pae::is_enabled() {
+			(base as *const u64).add(index).read_volatile()
+		} else {
+			(base as *const u32).add(index).read_volatile() as u64
+		}
+	})
+}
+
+fn write_entry(table: usize, index: usize, entry: u64) {
+	with_frame((table & !0xFFF) as u64, |frame| unsafe {
+		let base = frame.add(table & 0xFFF);
+		if pae::is_enabled() {
+			(base as *mut u64).add(index).write_volatile(entry);
+		} else {
+			(base as *mut u32).add(index).write_volatile(entry as u32);
+		}
+	})
+}
+
+fn read_table(table: usize) -> Vec<u64> {
+	let mut entries = Vec::with_capacity(self::entries());
+	with_frame(table as u64, |frame| unsafe {
+		for index in 0..entries.capacity() {
+			entries.push(if pae::is_enabled() {
+				(frame as *const u64).add(index).read_volatile()
+			} else {
+				(frame as *const u32).add(index).read_volatile() as u64
+			});
+		}
+	});
+	entries
+}
+
+fn write_table(table: usize, entries: &[u64]) {
+	with_frame(table as u64, |frame| unsafe {
+		for (index, entry) in entries.iter().enumerate() {
+			if pae::is_enabled() {
+				(frame as *mut u64).add(index).write_volatile(*entry);
+			} else {
+				(frame as *mut u32).add(index).write_volatile(*entry as u32);
+			}
+		}
+	});
+}
+
+fn zero_frame(address: usize) {
+	with_frame(address as u64, |frame| unsafe {
+		frame.write_bytes(0, 0x1000)
+	});
+}
+
+/// Copy a frame through `buffer`, there is a single scratch page.
+fn copy_frame(source: u64, destination: u64, buffer: &mut [u8]) {
+	with_frame(source, |frame| unsafe {
+		buffer
+			.as_mut_ptr()
+			.copy_from_nonoverlapping(frame, buffer.len())
+	});
+	with_frame(destination, |frame| unsafe {
+		frame.copy_from_nonoverlapping(buffer.as_ptr(), buffer.len())
+	});
+}
+
+fn put_frame(address: u64) -> Result<bool, PhysicalMemoryError> {
+	if address >= HIGH_MEMORY_START {
+		BITMAP.lock().free_high_frame(address).map(|_| true)
+	} else {
+		BITMAP.lock().put_frame(address as usize)
+	}
+}
+
+/// ```PageFlags::NO_EXECUTE``` only with NX on, 32 bits entries drop it on write.
+fn entry_flags(mut flags: PageFlags) -> PageFlags {
+	if !pae::nx_enabled() {
+		flags.remove(PageFlags::NO_EXECUTE);
+	}
+	flags
+}
+
+/// Directories of the space with the root `root`, 1 without PAE.
+fn directories(root: usize) -> ([usize; 4], usize) {
+	if pae::is_enabled() {
+		let mut directories = [0; 4];
+		for (pdpi, directory) in directories.iter_mut().enumerate() {
+			*directory = (read_entry(root, pdpi) & ADDRESS_MASK) as usize;
+		}
+		(directories, 4)
+	} else {
+		([root, 0, 0, 0], 1)
+	}
+}
+
+/// Directory table and index of the entry for `virtual_address` in the space `root`.
+fn directory_entry(root: usize, virtual_address: usize) -> (usize, usize) {
+	let slot = virtual_address >> slot_shift();
+	if pae::is_enabled() {
+		let directory = read_entry(root, slot / 512) & ADDRESS_MASK;
+		(directory as usize, slot % 512)
+	} else {
+		(root, slot)
+	}
+}
+
+/// ## Share_kernel_entry
+/// Copy the directory entry of `virtual_address`, just written in the active space,
+/// to every other space when it is in the shared range. \
+/// Called when a kernel page table or large page comes or goes, so the kernel
+/// looks the same from every space.
+pub fn share_kernel_entry(virtual_address: usize, entry: u64) {
+	let kernel = KERNEL_ROOT.load(Ordering::Relaxed);
+	// before init the boot directory is the only one
+	if kernel == 0 || !is_shared(virtual_address >> slot_shift()) {
+		return;
+	}
+	let active = ACTIVE_ROOT.load(Ordering::Relaxed);
+	let spaces = SPACES.lock();
+	for root in spaces.iter().copied().chain(iter::once(kernel)) {
+		if root != 0 && root != active {
+			let (directory, index) = directory_entry(root, virtual_address);
+			write_entry(directory, index, entry);
+		}
+	}
+}
+
+/// ## Switch_to
+/// Load `root` in CR3 and count the pages of its private tables. The directory locks are
+/// held so nothing edits the tables through the recursive slot meanwhile.
+fn switch_to(root: usize) {
+	let mut directory = PAGE_DIRECTORY.lock();
+	if pae::is_enabled() {
+		let (directories, _) = directories(root);
+		PAE_DIRECTORY.lock().set_directories(directories);
+	}
+	unsafe { asm!("mov cr3, {}", in(reg) root) };
+	ACTIVE_ROOT.store(root, Ordering::Relaxed);
+	directory.recount(USER_START..USER_END);
+}
+
+/// Called once paging is on, the boot directory becomes the kernel space.
+pub fn init() {
+	let root = if pae::is_enabled() {
+		pae::pdpt_address()
+	} else {
+		PDA
+	};
+	KERNEL_ROOT.store(root, Ordering::Relaxed);
+	ACTIVE_ROOT.store(root, Ordering::Relaxed);
+}
+
+/// Go back to the boot directory, ex) before dropping the active space.
+#[allow(unused)]
+pub fn activate_kernel() {
+	switch_to(KERNEL_ROOT.load(Ordering::Relaxed));
+}
+
+/// ## AddressSpace
+/// Page directory of its own for ```USER_START..USER_END```, every other directory
+/// entry is copied from the active one so the kernel tables are shared. \
+/// Kernel entries changed later are copied by ```share_kernel_entry```,
+/// at most ```MAX_SPACES``` spaces are alive at once. \
+/// The active space is edited through the recursive slot, the others through
+/// the scratch page of ```with_frame```.
+#[allow(unused)]
+pub struct AddressSpace {
+	root: usize,
+}
+
+#[allow(unused)]
+impl AddressSpace {
+	pub fn new() -> Result<AddressSpace, PhysicalMemoryError> {
+		let root = BITMAP.lock().alloc_page_table()?;
+		zero_frame(root);
+		// dropped on error, entries still 0 are skipped
+		let space = AddressSpace { root };
+		if pae::is_enabled() {
+			for pdpi in 0..4 {
+				let directory = BITMAP.lock().alloc_page_table()?;
+				zero_frame(directory);
+				// only the present bit is allowed in a PDPT entry
+				write_entry(root, pdpi, directory as u64 | PageFlags::PRESENT.bits());
+			}
+		}
+		// registered before the copy, read_table allocates and the heap may add
+		// a kernel table to a directory already copied
+		{
+			let mut spaces = SPACES.lock();
+			let slot = spaces
+				.iter_mut()
+				.find(|root| **root == 0)
+				.ok_or(PhysicalMemoryError::OutofMemory)?;
+			*slot = root;
+		}
+
+		let (kernel, _) = directories(ACTIVE_ROOT.load(Ordering::Relaxed));
+		let (directories, count) = directories(root);
+		for i in 0..count {
+			let mut entries = read_table(kernel[i]);
+			for (index, entry) in entries.iter_mut().enumerate() {
+				if !is_shared(i * self::entries() + index) {
+					*entry = 0;
+				}
+			}
+			write_table(directories[i], &entries);
+		}
+		let last = directories[count - 1];
+		let recursive = recursive_slot() % self::entries();
+		for (i, directory) in directories[..count].iter().enumerate() {
+			write_entry(
+				last,
+				recursive + i,
+				*directory as u64 | PageFlags::KERNEL.bits(),
+			);
+		}
+		Ok(space)
+	}
+
+	pub fn is_active(&self) -> bool {
+		ACTIVE_ROOT.load(Ordering::Relaxed) == self.root
+	}
+
+	pub fn activate(&self) {
+		switch_to(self.root);
+	}
+
+	/// Directory table and index of the entry for `virtual_address`.
+	fn directory_entry(&self, virtual_address: usize) -> (usize, usize) {
+		directory_entry(self.root, virtual_address)
+	}
+
+	/// Page table and index of the entry for `virtual_address`, None without a table.
+	fn table_entry(&self, virtual_address: usize) -> Option<(usize, usize)> {
+		let (directory, index) = self.directory_entry(virtual_address);
+		let entry = read_entry(directory, index);
+		if entry & 0x1 == 0 {
+			return None;
+		}
+		let pti = (virtual_address >> 12) & (entries() - 1);
+		Some(((entry & ADDRESS_MASK) as usize, pti))
+	}
+
+	/// ## Map_page
+	/// Map `virtual_address` to `physical_address`, only in ```USER_START..USER_END```. \
+	/// The physical address is 64 bits for PAE, cut to 32 bits otherwise.
+	pub fn map_page(
+		&mut self,
+		virtual_address: usize,
+		physical_address: u64,
+		flags: PageFlags,
+	) -> Result<(), PhysicalMemoryError> {
+		assert!(virtual_address & 0xFFF == 0, "Address is not 4KB aligned");
+		assert!(
+			(USER_START..USER_END).contains(&virtual_address),
+			"0x{:x} is shared with the kernel",
+			virtual_address
+		);
+		if self.is_active() {
+			return if pae::is_enabled() {
+				PAE_DIRECTORY
+					.lock()
+					.map_page(virtual_address, physical_address, flags)
+			} else {
+				PAGE_DIRECTORY
+					.lock()
+					.map_page(virtual_address, physical_address as usize, flags)
+			};
+		}
+		let flags = entry_flags(flags);
+
+		if self.table_entry(virtual_address).is_none() {
+			let table = BITMAP.lock().alloc_page_table()?;
+			zero_frame(table);
+			let (directory, index) = self.directory_entry(virtual_address);
+			write_entry(directory, index, table as u64 | PageFlags::KERNEL.bits());
+		}
+		self.allow_user(virtual_address, flags);
+		let (table, pti) = self.table_entry(virtual_address).unwrap();
+		assert!(
+			read_entry(table, pti) & 0x1 == 0,
+			"page entry already present. address: 0x{:x}, pti: {}",
+			virtual_address,
+			pti
+		);
+		write_entry(table, pti, physical_address | flags.bits());
+		Ok(())
+	}
+
+	/// A user page needs the user bit in its directory entry too.
+	fn allow_user(&self, virtual_address: usize, flags: PageFlags) {
+		if flags.contains(PageFlags::USER) {
+			let (directory, index) = self.directory_entry(virtual_address);
+			let entry = read_entry(directory, index);
+			write_entry(directory, index, entry | PageFlags::USER.bits());
+		}
+	}
+
+	/// Remove the mapping and drop its reference on the frame.
+	pub fn unmap_page(&mut self, virtual_address: usize) -> Result<(), PhysicalMemoryError> {
+		assert!(
+			(USER_START..USER_END).contains(&virtual_address),
+			"0x{:x} is shared with the kernel",
+			virtual_address
+		);
+		if self.is_active() {
+			return PAGE_DIRECTORY.lock().unmap_page(virtual_address);
+		}
+		let entry = self
+			.table_entry(virtual_address)
+			.map(|(table, pti)| (table, pti, read_entry(table, pti)))
+			.filter(|(_, _, entry)| entry & 0x1 != 0);
+		let Some((table, pti, entry)) = entry else {
+			panic!(
+				"page entry not present. virtual address: 0x{:x}",
+				virtual_address
+			);
+		};
+		put_frame(entry & ADDRESS_MASK)?;
+		write_entry(table, pti, 0);
+		// no counts for an inactive space, the table is scanned, swapped pages keep it
+		if read_table(table).iter().all(|entry| *entry == 0) {
+			let (directory, index) = self.directory_entry(virtual_address);
+			write_entry(directory, index, 0);
+			BITMAP.lock().free_page_table(table)?;
+		}
+		Ok(())
+	}
+
+	/// Physical frame mapped at `virtual_address`, None if not present.
+	pub fn translate(&self, virtual_address: usize) -> Option<u64> {
+		self.query(virtual_address).map(|(frame, _)| frame)
+	}
+
+	/// Physical frame and flags of the page at `virtual_address`, None if not mapped.
+	pub fn query(&self, virtual_address: usize) -> Option<(u64, PageFlags)> {
+		let (table, pti) = self.table_entry(virtual_address)?;
+		let entry = read_entry(table, pti);
+		(entry & 0x1 != 0).then(|| (entry & ADDRESS_MASK, PageFlags::from_entry(entry)))
+	}
+
+	/// ## Protect
+	/// Same as ```PageDirectory::protect```, an inactive space has nothing in the TLB.
+	pub fn protect(
+		&mut self,
+		range: Range<usize>,
+		flags: PageFlags,
+	) -> Result<(), VirtualMemoryError> {
+		assert!(
+			(USER_START..USER_END).contains(&range.start)
+				&& (USER_START..=USER_END).contains(&range.end),
+			"0x{:x}-0x{:x} is shared with the kernel",
+			range.start,
+			range.end
+		);
+		if self.is_active() {
+			return PAGE_DIRECTORY.lock().protect(range, flags);
+		}
+		let flags = entry_flags(flags);
+		let pages = (range.start & !0xFFF..range.end).step_by(0x1000);
+		if pages.clone().any(|address| self.query(address).is_none()) {
+			return Err(VirtualMemoryError::NotMapped);
+		}
+		for address in pages {
+			self.allow_user(address, flags);
+			let (table, pti) = self.table_entry(address).unwrap();
+			let entry = read_entry(table, pti);
+			let flags = PageFlags::from_entry(entry).protected(flags);
+			write_entry(table, pti, entry & ADDRESS_MASK | flags.bits());
+		}
+		Ok(())
+	}
+
+	/// Directory number, entry index and address of each private page table.
+	fn private_tables(&self) -> Vec<(usize, usize, usize)> {
+		let mut tables = Vec::new();
+		let (directories, count) = directories(self.root);
+		for (i, directory) in directories[..count].iter().enumerate() {
+			// a PAE space dropped while being built
+			if *directory == 0 {
+				continue;
+			}
+			for (index, entry) in read_table(*directory).into_iter().enumerate() {
+				if is_private(i * entries() + index) && entry & 0x1 != 0 {
+					tables.push((i, index, (entry & ADDRESS_MASK) as usize));
+				}
+			}
+		}
+		tables
+	}
+
+	/// ## Try_clone
+	/// New space with a copy of every private page, the shared range is the same. \
+	/// Pages of shared memory objects are mapped in both instead.
+	pub fn try_clone(&self) -> Result<AddressSpace, PhysicalMemoryError> {
+		let space = AddressSpace::new()?;
+		let (directories, _) = directories(space.root);
+		let mut buffer = vec![0u8; 0x1000];
+		for (i, index, source) in self.private_tables() {
+			let table = BITMAP.lock().alloc_page_table()?;
+			zero_frame(table);
+			write_entry(
+				directories[i],
+				index,
+				table as u64 | PageFlags::USER_RW.bits(),
+			);
+			for (pti, entry) in read_table(source).into_iter().enumerate() {
+				if entry & 0x1 == 0 {
+					// a swapped out page gets a slot of its own
+					#[cfg(feature = "swap")]
+					if entry != 0 {
+						let copy = swap::duplicate(entry, &mut buffer)
+							.map_err(|_| PhysicalMemoryError::OutofMemory)?;
+						write_entry(table, pti, copy);
+					}
+					continue;
+				}
+				if entry & PageFlags::SHARED.bits() != 0 {
+					BITMAP.lock().get_frame((entry & ADDRESS_MASK) as usize)?;
+					write_entry(table, pti, entry);
+					continue;
+				}
+				let frame = physicalmemory::alloc_frame_or_evict()?;
+				copy_frame(entry & ADDRESS_MASK, frame as u64, &mut buffer);
+				write_entry(table, pti, frame as u64 | (entry & !ADDRESS_MASK));
+			}
+		}
+		Ok(space)
+	}
+
+	/// ## Clone_cow
+	/// New space sharing every private frame, the writable pages become read only
+	/// with ```PageFlags::COW``` on both sides and are copied on the first write. \
+	/// Pages of shared memory objects stay writable in both. \
+	/// Frames without a reference count, ex) over 4GB, are copied right away.
+	pub fn clone_cow(&mut self) -> Result<AddressSpace, PhysicalMemoryError> {
+		let space = AddressSpace::new()?;
+		let (directories, _) = directories(space.root);
+		let mut buffer = vec![0u8; 0x1000];
+		for (i, index, source) in self.private_tables() {
+			let table = BITMAP.lock().alloc_page_table()?;
+			zero_frame(table);
+			write_entry(
+				directories[i],
+				index,
+				table as u64 | PageFlags::USER_RW.bits(),
+			);
+			for (pti, entry) in read_table(source).into_iter().enumerate() {
+				if entry & 0x1 == 0 {
+					// a swapped out page gets a slot of its own
+					#[cfg(feature = "swap")]
+					if entry != 0 {
+						let copy = swap::duplicate(entry, &mut buffer)
+							.map_err(|_| PhysicalMemoryError::OutofMemory)?;
+						write_entry(table, pti, copy);
+					}
+					continue;
+				}
+				let frame = entry & ADDRESS_MASK;
+				let shared =
+					frame < HIGH_MEMORY_START && BITMAP.lock().get_frame(frame as usize).is_ok();
+				if !shared {
+					let copy = physicalmemory::alloc_frame_or_evict()?;
+					copy_frame(frame, copy as u64, &mut buffer);
+					write_entry(table, pti, copy as u64 | (entry & !ADDRESS_MASK));
+					continue;
+				}
+				let mut entry = entry;
+				let cow = PageFlags::WRITABLE.bits() | PageFlags::SHARED.bits();
+				if entry & cow == PageFlags::WRITABLE.bits() {
+					entry = entry & !PageFlags::WRITABLE.bits() | PageFlags::COW.bits();
+					write_entry(source, pti, entry);
+				}
+				write_entry(table, pti, entry);
+			}
+		}
+		if self.is_active() {
+			// the pages made read only, at once
+			flush_all();
+		}
+		Ok(space)
+	}
+}
+
+impl Drop for AddressSpace {
+	/// Free the private pages, swap slots and tables, then the directories.
+	fn drop(&mut self) {
+		assert!(!self.is_active(), "dropping the active address space");
+		if let Some(slot) = SPACES.lock().iter_mut().find(|root| **root == self.root) {
+			*slot = 0;
+		}
+		for (_, _, table) in self.private_tables() {
+			for entry in read_table(table) {
+				#[cfg(feature = "swap")]
+				swap::free_entry(entry);
+				if entry & 0x1 != 0 {
+					// frames outside the allocator, ex) MMIO
+					let _ = put_frame(entry & ADDRESS_MASK);
+				}
+			}
+			BITMAP.lock().free_page_table(table).unwrap();
+		}
+		let (directories, count) = directories(self.root);
+		let mut bitmap = BITMAP.lock();
+		if pae::is_enabled() {
+			for directory in directories[..count].iter().filter(|d| **d != 0) {
+				bitmap.free_page_table(*directory).unwrap();
+			}
+		}
+		bitmap.free_page_table(self.root).unwrap();
+	}
+}
@@ -0,0 +1,162 @@
+use crate::memory::pageflags::PageFlags;
+use crate::memory::physicalmemory::{self, BITMAP};
+#[cfg(feature = "swap")]
+use crate::memory::swap;
+use crate::memory::virtualmemory::{with_frame, PAGE_DIRECTORY};
+use spin::Mutex;
+
+const MAX_REGIONS: usize = 32;
+
+#[derive(Debug)]
+pub enum DemandError {
+	TooManyRegions,
+	Overlap,
+	NotFound,
+}
+
+/// Virtual range `[start, end)` backed on first touch.
+#[derive(Debug, Clone, Copy)]
+pub struct LazyRegion {
+	pub start: usize,
+	pub end: usize,
+	pub flags: PageFlags,
+	// Pages with a frame so far.
+	pub committed: usize,
+}
+
+impl LazyRegion {
+	pub fn pages(&self) -> usize {
+		(self.end - self.start) / 0x1000
+	}
+}
+
+const EMPTY_REGION: LazyRegion = LazyRegion {
+	start: 0,
+	end: 0,
+	flags: PageFlags::empty(),
+	committed: 0,
+};
+
+pub struct LazyRegions {
+	regions: [LazyRegion; MAX_REGIONS],
+	count: usize,
+}
+
+/// Kept apart from the heap allocators, the page fault handler can run while
+/// one of them is locked.
+pub static LAZY_REGIONS: Mutex<LazyRegions> = Mutex::new(LazyRegions {
+	regions: [EMPTY_REGION; MAX_REGIONS],
+	count: 0,
+});
+
+impl LazyRegions {
+	pub fn regions(&self) -> &[LazyRegion] {
+		&self.regions[..self.count]
+	}
+
+	/// Pages with a frame in every region.
+	pub fn committed(&self) -> usize {
+		self.regions().iter().map(|r| r.committed).sum()
+	}
+
+	/// ## Reserve
+	/// Add a region, its pages get a zeroed frame mapped with `flags` on first touch.
+	pub fn reserve(
+		&mut self,
+		start: usize,
+		end: usize,
+		flags: PageFlags,
+	) -> Result<(), DemandError> {
+		assert!(start % 0x1000 == 0, "Address is not 4KB aligned");
+		let end = (end + 0xFFF) & !0xFFF;
+		if self.count == MAX_REGIONS {
+			return Err(DemandError::TooManyRegions);
+		}
+		if self
+			.regions()
+			.iter()
+			.any(|r| r.start < end && start < r.end)
+		{
+			return Err(DemandError::Overlap);
+		}
+		self.regions[self.count] = LazyRegion {
+			start,
+			end,
+			flags,
+			committed: 0,
+		};
+		self.count += 1;
+		Ok(())
+	}
+
+	/// Remove the region starting at `start` and unmap its committed pages.
+	pub fn release(&mut self, start: usize) -> Result<LazyRegion, DemandError> {
+		let index = self
+			.regions()
+			.iter()
+			.position(|r| r.start == start)
+			.ok_or(DemandError::NotFound)?;
+		let region = self.regions[index];
+		let mut directory = PAGE_DIRECTORY.lock();
+		for address in (region.start..region.end).step_by(0x1000) {
+			if directory.query(address).is_some() {
+				directory.unmap_page(address).unwrap();
+			}
+			#[cfg(feature = "swap")]
+			swap::discard(&mut directory, address);
+		}
+		self.regions.copy_within(index + 1..self.count, index);
+		self.count -= 1;
+		Ok(region)
+	}
+
+	pub fn find(&mut self, address: usize) -> Option<&mut LazyRegion> {
+		self.regions[..self.count]
+			.iter_mut()
+			.find(|r| r.start <= address && address < r.end)
+	}
+}
+
+/// ## Handle_fault
+/// Back the page at `address` if it is in a region, called for a not present page. \
+/// A swapped out page is read back, any other gets a zeroed frame. \
+/// Return false when the fault is not ours or there is no memory left.
+pub fn handle_fault(address: usize) -> bool {
+	let page = address & !0xFFF;
+	// unlocked while taking the frame, it may evict a page of a region
+	let Some(flags) = LAZY_REGIONS.lock().find(address).map(|r| r.flags) else {
+		return false;
+	};
+	let Ok(frame) = physicalmemory::alloc_frame_or_evict() else {
+		return false;
+	};
+	#[cfg(feature = "swap")]
+	match swap::swap_in(page, frame, flags) {
+		Ok(true) => return commit(address),
+		Ok(false) => {}
+		Err(_) => {
+			BITMAP.lock().free_frame(frame).unwrap();
+			return false;
+		}
+	}
+	with_frame(frame as u64, |page| unsafe { page.write_bytes(0, 0x1000) });
+	let mapped = PAGE_DIRECTORY.lock().map_page(page, frame, flags);
+	// the page table may need the last frame
+	#[cfg(feature = "swap")]
+	let mapped = match mapped {
+		Err(_) if swap::evict().is_ok() => PAGE_DIRECTORY.lock().map_page(page, frame, flags),
+		mapped => mapped,
+	};
+	if mapped.is_err() {
+		BITMAP.lock().free_frame(frame).unwrap();
+		return false;
+	}
+	commit(address)
+}
+
+fn commit(address: usize) -> bool {
+	if let Some(region) = LAZY_REGIONS.lock().find(address) {
+		region.committed += 1;
+	}
+	true
+}
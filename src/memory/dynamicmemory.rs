@@ -1,12 +1,20 @@
-use crate::memory::physicalmemory::{BITMAP, N_FRAMES};
-use crate::memory::virtualmemory::PAGE_DIRECTORY;
+use crate::memory::demand::LAZY_REGIONS;
+use crate::memory::pageflags::PageFlags;
+use crate::memory::physicalmemory::BITMAP;
+use crate::memory::virtualmemory::{large_page_size, large_pages_enabled, PAGE_DIRECTORY};
+use crate::memory::vrange::VirtualRanges;
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
 
 const MAX_ORDER: usize = 10;
 const PAGE_SIZE: usize = 0x1000;
 const LIST_COUNT: usize = 1000;
-const LIST_COUNT_INIT_MAX: usize = (LIST_COUNT / 10) * 3;
+
+// Virtual ranges of the heaps, `[start, end)`.
+pub const USER_HEAP_START: usize = 0x300000;
+pub const USER_HEAP_END: usize = 0x4000_0000; // ≒ 1GB, under the private range of the address spaces
+pub const KERNEL_HEAP_START: usize = 0x800B_6000;
+pub const KERNEL_HEAP_END: usize = 0xBFFE_0000; // ≒ 1GB
 
 #[derive(PartialEq, Debug)]
 pub enum Privilege {
@@ -19,8 +27,10 @@ pub struct HeapAllocator {
 	free_lists: [[usize; LIST_COUNT]; MAX_ORDER + 1],
 	free_counts: [usize; MAX_ORDER + 1],
 	privilege: Privilege,
-	next_virtual_addr: usize,
+	// Free parts of the virtual range of the heap.
+	ranges: VirtualRanges,
 	paging_status: bool,
+	used_pages: usize,
 }
 
 impl HeapAllocator {
@@ -29,12 +39,16 @@ impl HeapAllocator {
 			free_lists: [[0; LIST_COUNT]; MAX_ORDER + 1],
 			free_counts: [0; MAX_ORDER + 1],
 			privilege: Privilege::None,
-			next_virtual_addr: 0,
+			ranges: VirtualRanges::new(0, 0, 0),
 			paging_status: false,
+			used_pages: 0,
 		}
 	}
 
-	#[allow(clippy::needless_range_loop)]
+	/// ## Init
+	/// Set the virtual range of the heap. \
+	/// The free lists start empty, blocks are taken from ```BITMAP``` when they run out
+	/// and the heap keeps them allocated there once freed.
 	pub fn init(
 		&mut self,
 		start_addr: usize,
@@ -45,58 +59,8 @@ impl HeapAllocator {
 		assert!(start_addr % 0x1000 == 0, "Address is not 4KB aligned");
 		assert!(end_addr % 0x1000 == 0, "Address is not 4KB aligned");
 		self.privilege = privilege;
-		self.next_virtual_addr = start_addr;
+		self.ranges = VirtualRanges::new(start_addr, end_addr, 0);
 		self.paging_status = paging_status;
-
-		if paging_status {
-			PAGE_DIRECTORY.lock().init_directory(start_addr, end_addr);
-		}
-		let mut frame = start_addr / PAGE_SIZE;
-		let end_frame = end_addr / PAGE_SIZE;
-		let mut index = 0;
-		let mut j;
-
-		while frame < end_frame {
-			let mut allocated = false;
-			for order in (0..=MAX_ORDER).rev() {
-				let block_size = 1 << order;
-				if frame + block_size <= end_frame {
-					let mut can_allocate = true;
-					j = 0;
-					while j < block_size && frame + j + index < N_FRAMES {
-						if !BITMAP.lock().is_frame_free(frame + j + index) {
-							can_allocate = false;
-							break;
-						}
-						j += 1;
-					}
-					index += j;
-
-					if can_allocate {
-						let addr = frame * PAGE_SIZE;
-						if self.free_counts[order] < LIST_COUNT_INIT_MAX {
-							self.free_lists[order][self.free_counts[order]] = addr;
-							self.free_counts[order] += 1;
-							frame += block_size;
-							allocated = true;
-							break;
-						}
-					}
-				}
-			}
-			if !allocated {
-				frame += 1;
-			}
-		}
-		// crate::println!(
-		// 	"{} list: {:?}",
-		// 	if self.privilege == Privilege::User {
-		// 		"User"
-		// 	} else {
-		// 		"Kernel"
-		// 	},
-		// 	self.free_counts
-		// );
 	}
 
 	fn size_to_order(&self, size: usize) -> Option<usize> {
@@ -113,29 +77,6 @@ impl HeapAllocator {
 		}
 	}
 
-	/// ### Similar to size_to_order but ends at MAX_ORDER and returns
-	/// return value \
-	/// - Some: 0 > usize > max_order \
-	/// - None: size < PAGE_SIZE
-	fn match_order(&self, size: usize) -> Option<usize> {
-		let mut order: usize = 0;
-		let mut block_size = PAGE_SIZE;
-
-		if block_size > size || size % PAGE_SIZE != 0 {
-			None
-		} else {
-			while block_size < size && order < MAX_ORDER {
-				block_size *= 2;
-				order += 1;
-			}
-			// crate::println!("{}, {} < {}, ", order, block_size, size);
-			if block_size > size {
-				order -= 1;
-			}
-			Some(order)
-		}
-	}
-
 	pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
 		let size = layout.size().max(layout.align());
 		let order = self.size_to_order(size);
@@ -156,6 +97,8 @@ impl HeapAllocator {
 					if higher_order <= MAX_ORDER {
 						// crate::println!("allocate_split in");
 						self.allocate_split(higher_order, o)
+					} else if let Some(physical_address) = self.take_block(o) {
+						self.allocate_address(physical_address, o)
 					} else {
 						// crate::println!("allocate_merge in higher_order: {}", higher_order);
 						self.allocate_merge(o)
@@ -173,45 +116,50 @@ impl HeapAllocator {
 		}
 	}
 
-	fn allocate_large(&mut self, size: usize) -> *mut u8 {
-		let mut remaining_size = size as i64;
-		let mut base_addr: *mut u8 = null_mut();
-		let mut ret;
+	fn flags(&self) -> PageFlags {
+		if self.privilege == Privilege::Kernel {
+			PageFlags::KERNEL
+		} else {
+			PageFlags::USER_RW
+		}
+	}
 
-		while remaining_size > 0 {
-			let o = self.match_order(remaining_size as usize).unwrap();
-			let block_size = (1 << o) * PAGE_SIZE as i64;
-			// crate::println!(
-			// 	"alloc large remaining_size : {}, block_size {}, order {}",
-			// 	remaining_size,
-			// 	block_size,
-			// 	o
-			// );
-			if self.free_counts[o] > 0 {
-				let physical_address = self.free_lists[o][self.free_counts[o] - 1];
-				self.free_counts[o] -= 1;
-				ret = self.allocate_address(physical_address, o);
-			} else {
-				let mut higher_order = o + 1;
-				while higher_order <= MAX_ORDER && self.free_counts[higher_order] == 0 {
-					higher_order += 1;
-				}
+	/// Allocations over the biggest block are reserved without frames,
+	/// the page fault handler backs them on first touch.
+	fn is_lazy(&self, layout: Layout) -> bool {
+		self.size_to_order(layout.size().max(layout.align()))
+			.is_none()
+	}
 
-				if higher_order <= MAX_ORDER {
-					// crate::println!("large allocate_split in");
-					ret = self.allocate_split(higher_order, o);
-				} else {
-					// crate::println!("allocate_merge in higher_order: {}", higher_order);
-					ret = self.allocate_merge(o);
-				}
-			}
-			if base_addr.is_null() {
-				// crate::println!("large ret in : 0x{:x}", ret as usize);
-				base_addr = ret;
+	/// ## Allocate_large
+	/// Reserve the virtual range as a lazy region of ```demand```, no frame is taken. \
+	/// Its pages read as zero.
+	fn allocate_large(&mut self, size: usize) -> *mut u8 {
+		let size = size.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+		let Ok(virtual_address) = self.ranges.alloc(size, PAGE_SIZE) else {
+			return null_mut();
+		};
+		match LAZY_REGIONS
+			.lock()
+			.reserve(virtual_address, virtual_address + size, self.flags())
+		{
+			Ok(()) => virtual_address as *mut u8,
+			Err(_) => {
+				self.ranges.free(virtual_address, size).unwrap();
+				null_mut()
 			}
-			remaining_size -= block_size;
 		}
-		base_addr
+	}
+
+	/// A 4MB block aligned in physical memory gets the same virtual alignment,
+	/// so it can be mapped with large pages.
+	fn block_align(physical_address: usize, order: usize) -> usize {
+		let large = large_page_size();
+		if large_pages_enabled() && PAGE_SIZE << order >= large && physical_address % large == 0 {
+			large
+		} else {
+			PAGE_SIZE
+		}
 	}
 
 	fn allocate_split(&mut self, higher_order: usize, target_order: usize) -> *mut u8 {
@@ -231,10 +179,7 @@ impl HeapAllocator {
 			// 	higher_order,
 			// 	target_order
 			// );
-			if self.free_counts[current_order] < LIST_COUNT {
-				self.free_lists[current_order][self.free_counts[current_order]] = buddy;
-				self.free_counts[current_order] += 1;
-			}
+			self.free_block(buddy, current_order);
 		}
 		self.allocate_address(physical_address, target_order)
 	}
@@ -259,64 +204,96 @@ impl HeapAllocator {
 		null_mut()
 	}
 
-	fn allocate_address(&mut self, physical_address: usize, order: usize) -> *mut u8 {
-		let virtual_address = self.next_virtual_addr;
-		let num_pages = 1 << order;
-		for i in 0..num_pages {
-			let cur_virtual_addr = virtual_address + (i * PAGE_SIZE);
-			let cur_physical_addr = physical_address + (i * PAGE_SIZE);
-
-			// if i == 0 {
-			//     crate::println!(
-			//         "allocate : physical 0x{:x}, virtual 0x{:x}, pages {}/{}, order {}",
-			//         cur_physical_addr,
-			//         cur_virtual_addr,
-			//         i + 1,
-			//         num_pages,
-			//         order
-			//     );
-			// }
-			// crate::println!(
-			//     "allocate : physical 0x{:x}, virtual 0x{:x}, pages {}/{}, order {}",
-			//     cur_physical_addr,
-			//     cur_virtual_addr,
-			//     i + 1,
-			//     num_pages,
-			//     order
-			// );
+	/// ## Take_block
+	/// New block of `order` from ```BITMAP```, aligned on its size up to a large page. \
+	/// Its frames stay allocated there while the block is in the heap.
+	fn take_block(&mut self, order: usize) -> Option<usize> {
+		let align = (PAGE_SIZE << order).min(large_page_size());
+		BITMAP
+			.lock()
+			.alloc_frames(1 << order, align, usize::MAX)
+			.ok()
+	}
 
+	/// ## Free_block
+	/// Put a block in its free list, or give it back to ```BITMAP``` when the list is full.
+	fn free_block(&mut self, physical_address: usize, order: usize) {
+		if self.free_counts[order] < LIST_COUNT {
+			self.free_lists[order][self.free_counts[order]] = physical_address;
+			self.free_counts[order] += 1;
+		} else {
 			BITMAP
 				.lock()
-				.alloc_frame_address(cur_physical_addr)
+				.free_frames(physical_address, 1 << order)
 				.unwrap();
-			if self.paging_status {
-				PAGE_DIRECTORY
-					.lock()
-					.map_page(
-						cur_virtual_addr,
-						cur_physical_addr,
-						if self.privilege == Privilege::Kernel {
-							0x3
-						} else {
-							0x7
-						},
-					)
-					.unwrap();
-			}
 		}
+	}
 
-		self.next_virtual_addr += num_pages * PAGE_SIZE;
-		if self.paging_status {
-			virtual_address as *mut u8
+	/// ## Unmap_block
+	/// Unmap a block but keep its frames, the references dropped by the unmap
+	/// are taken again first.
+	fn unmap_block(virtual_address: usize, physical_address: usize, num_pages: usize) {
+		let mut bitmap = BITMAP.lock();
+		for i in 0..num_pages {
+			bitmap.get_frame(physical_address + i * PAGE_SIZE).unwrap();
+		}
+		drop(bitmap);
+		PAGE_DIRECTORY
+			.lock()
+			.unmap_range(virtual_address, num_pages * PAGE_SIZE)
+			.unwrap();
+	}
+
+	fn allocate_address(&mut self, physical_address: usize, order: usize) -> *mut u8 {
+		let num_pages = 1 << order;
+		let virtual_address = if self.paging_status {
+			let align = Self::block_align(physical_address, order);
+			match self.ranges.alloc(num_pages * PAGE_SIZE, align) {
+				Ok(address) => address,
+				Err(_) => {
+					// the block goes back untouched
+					self.free_block(physical_address, order);
+					return null_mut();
+				}
+			}
 		} else {
-			physical_address as *mut u8
+			physical_address
+		};
+		if self.paging_status {
+			let size = num_pages * PAGE_SIZE;
+			let mut directory = PAGE_DIRECTORY.lock();
+			// a 4MB block gets large pages when it is aligned
+			if directory
+				.map_range(virtual_address, physical_address, size, self.flags())
+				.is_err()
+			{
+				// no frame for a page table, the part already mapped is undone
+				let mut mapped = 0;
+				while mapped < size {
+					match directory.query(virtual_address + mapped) {
+						Some((_, flags)) if flags.contains(PageFlags::LARGE) => {
+							mapped += large_page_size()
+						}
+						Some(_) => mapped += PAGE_SIZE,
+						None => break,
+					}
+				}
+				drop(directory);
+				Self::unmap_block(virtual_address, physical_address, mapped / PAGE_SIZE);
+				self.ranges.free(virtual_address, size).unwrap();
+				self.free_block(physical_address, order);
+				return null_mut();
+			}
 		}
+
+		self.used_pages += num_pages;
+		virtual_address as *mut u8
 	}
 
 	pub fn deallocate(&mut self, addr: *mut u8, layout: Layout) {
 		let size = layout.size().max(layout.align());
 		let order = self.size_to_order(size);
-		let mut virtual_address = addr as usize;
+		let virtual_address = addr as usize;
 
 		// crate::println!("{:?}", layout);
 		// crate::println!("before list: {:?}", self.free_counts);
@@ -328,79 +305,48 @@ impl HeapAllocator {
 				// virtual
 				// if paging == true
 				let num_pages = 1 << order;
+				self.used_pages -= num_pages;
 				if self.paging_status {
-					self.next_virtual_addr -= num_pages * PAGE_SIZE;
 					let physical_address = PAGE_DIRECTORY.lock().translate(virtual_address);
-					self.free_lists[order][self.free_counts[order]] = physical_address;
-					self.free_counts[order] += 1;
-				}
-				for i in 0..num_pages {
-					let virtual_addr = virtual_address + i * PAGE_SIZE;
-					if self.paging_status {
-						PAGE_DIRECTORY.lock().unmap_page(virtual_addr).unwrap();
-					} else {
-						BITMAP.lock().free_frame(virtual_addr).unwrap();
-					}
+					Self::unmap_block(virtual_address, physical_address, num_pages);
+					self.ranges
+						.free(virtual_address, num_pages * PAGE_SIZE)
+						.unwrap();
+					self.free_block(physical_address, order);
+				} else {
+					self.free_block(virtual_address, order);
 				}
 			}
 			None => {
-				// crate::println!("Inside partial deallocate");
 				assert!(self.paging_status, "Without paging, kfree max size is 4mb");
-				let mut remain_size: usize = size;
-				// let mut dealloc_size: usize = 0;
-				while remain_size != 0 {
-					// crate::println!("deallocate: inside loop: rs {}, ds {}, va 0x{:X}", remain_size, dealloc_size, virtual_address);
-					// crate::println!("list: {:?}", self.free_counts);
-					match self.match_order(remain_size) {
-						Some(mut order) => {
-							// crate::println!("deallocate: size {}, order {}, pa 0x{:X}", size, order, virtual_address);
-							// crate::println!("deallocate: in some: order {} rs {}", order, remain_size);
-							// crate::println!("order: {} free list: {:?} free count: {:?}", order, self.free_lists[order], self.free_counts);
-							// physical
-							while self.free_counts[order] == LIST_COUNT {
-								// assert!(order == 0, "deallocation: {:?}", self.free_counts);
-								order -= 1;
-							}
-							let num_pages = 1 << order;
-							let physical_address = PAGE_DIRECTORY.lock().translate(virtual_address);
-							self.free_lists[order][self.free_counts[order]] = physical_address;
-							self.free_counts[order] += 1;
-							// virtual
-							for i in 0..num_pages {
-								self.next_virtual_addr -= num_pages * PAGE_SIZE;
-								let virtual_addr = virtual_address + i * PAGE_SIZE;
-								PAGE_DIRECTORY.lock().unmap_page(virtual_addr).unwrap();
-							}
-							// crate::println!("deallocated va: 0x{:X}", virtual_address + num_pages * PAGE_SIZE);
-							virtual_address += num_pages * PAGE_SIZE;
-							// dealloc_size = num_pages * PAGE_SIZE;
-							remain_size -= num_pages * PAGE_SIZE;
-							// crate::println!("deallocation process: freed {}, left {}", dealloc_size, remain_size);
-						}
-						None => {
-							// crate::println!("deallocate: in None");
-							// if remain_size == 0 {
-							// 	crate::println!(
-							// 		"deallocated successfully: no leaks ({})",
-							// 		remain_size
-							// 	);
-							// } else {
-							// 	crate::println!(
-							// 		"deallocate failed: possible leaks ({})",
-							// 		remain_size
-							// 	);
-							// }
-							break;
-						}
-					}
-				}
-				// crate::println!("partial deallocation leaks: ({})", remain_size);
-				// crate::println!("size: {}, addr: 0x{:X}", size, virtual_address);
+				let region = LAZY_REGIONS.lock().release(virtual_address).unwrap();
+				self.ranges
+					.free(region.start, region.end - region.start)
+					.unwrap();
 			}
 		}
 		// crate::println!("after list: {:?}", self.free_counts);
 	}
 
+	/// Pages currently handed out by this allocator.
+	pub fn used_pages(&self) -> usize {
+		self.used_pages
+	}
+
+	/// Free parts of the virtual range.
+	pub fn ranges(&self) -> &VirtualRanges {
+		&self.ranges
+	}
+
+	/// Pages left in the free lists.
+	pub fn free_pages(&self) -> usize {
+		self.free_counts
+			.iter()
+			.enumerate()
+			.map(|(order, count)| count << order)
+			.sum()
+	}
+
 	pub fn print_free_list(&self) {
 		crate::println!(
 			"{}, count: {:?}",
@@ -452,6 +398,8 @@ impl HeapAllocator {
 
 					if higher_order <= MAX_ORDER {
 						self.allocate_split(higher_order, o)
+					} else if let Some(physical_address) = self.take_block(o) {
+						self.allocate_address(physical_address, o)
 					} else {
 						// can not sure contiguous physical memory
 						null_mut()
@@ -477,17 +425,18 @@ impl HeapAllocator {
 
 		match order {
 			Some(order) => {
-				self.free_lists[order][self.free_counts[order]] = ptr;
-				self.free_counts[order] += 1;
+				let physical_address = if self.paging_status {
+					PAGE_DIRECTORY.lock().translate(ptr)
+				} else {
+					ptr
+				};
 				let num_pages = 1 << order;
-				for i in 0..num_pages {
-					let virtual_addr = ptr + i * PAGE_SIZE;
-					if self.paging_status {
-						PAGE_DIRECTORY.lock().unmap_page(virtual_addr).unwrap();
-					} else {
-						BITMAP.lock().free_frame(virtual_addr).unwrap();
-					}
+				self.used_pages -= num_pages;
+				if self.paging_status {
+					Self::unmap_block(ptr, physical_address, num_pages);
+					self.ranges.free(ptr, num_pages * PAGE_SIZE).unwrap();
 				}
+				self.free_block(physical_address, order);
 			}
 			None => {
 				assert!(
@@ -530,6 +479,18 @@ unsafe impl GlobalAlloc for Locked<HeapAllocator> {
 		// loop {}
 	}
 
+	/// Lazy regions are zero on first touch, writing them would commit every page.
+	unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+		let mut allocator = self.lock();
+		let lazy = allocator.paging_status && allocator.is_lazy(layout);
+		let address = allocator.allocate(layout);
+		drop(allocator);
+		if !address.is_null() && !lazy {
+			address.write_bytes(0, layout.size());
+		}
+		address
+	}
+
 	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
 		self.lock().deallocate(ptr, layout)
 	}
@@ -3,10 +3,20 @@
 #![allow(unused)]
 
 use crate::memory;
+use crate::memory::addressspace::{self, AddressSpace, USER_START};
+use crate::memory::demand::LAZY_REGIONS;
 use crate::memory::dynamicmemory::{KERNEL_ALLOCATOR, USER_ALLOCATOR};
+use crate::memory::pageflags::PageFlags;
+use crate::memory::physicalmemory::{self, BITMAP};
+use crate::memory::shm;
+#[cfg(feature = "swap")]
+use crate::memory::swap::SWAP;
+use crate::memory::virtualmemory::{self, with_frame};
 use crate::println;
+use crate::time::tsc;
 use alloc::vec;
 use core::alloc::Layout;
+use core::hint::black_box;
 
 fn simple_str_test() {
 	{
@@ -130,6 +140,8 @@ fn vec_alloc_test(paging_status: bool) {
 			let vec10: vec::Vec<u8> = vec![0; 500 * 1024 * 1024]; // 500MB
 			println!("----------after allocation------------");
 			KERNEL_ALLOCATOR.lock().print_free_list();
+			// the 3 big vectors are lazy, only the touched pages have a frame
+			println!("lazy committed: {} pages", LAZY_REGIONS.lock().committed());
 		}
 	}
 	println!("***************out of block, all drop***************");
@@ -145,3 +157,226 @@ pub fn alloc_test(paging_status: bool) {
 	user_alloc_test(paging_status);
 	kalloc_test();
 }
+
+/// ## Frame_alloc_bench
+/// Compare the former linear bitmap scan with the summary bitmap search. \
+/// 256MB of frames are taken first, so both searches start from the bottom of memory
+/// and have to skip them, like after a wraparound.
+pub fn frame_alloc_bench() {
+	const FILL: usize = 65536;
+	const ROUNDS: u64 = 1000;
+
+	println!("----------frame allocation bench------------");
+	let mut frames = vec::Vec::with_capacity(FILL);
+	let start = tsc::now_ns();
+	{
+		let mut bitmap = BITMAP.lock();
+		for _ in 0..FILL {
+			match bitmap.alloc_frame() {
+				Ok(frame) => frames.push(frame),
+				Err(_) => break,
+			}
+		}
+	}
+	let fill = (tsc::now_ns() - start) / frames.len().max(1) as u64;
+	println!(
+		"alloc_frame: {} frames, {} ns per frame",
+		frames.len(),
+		fill
+	);
+
+	{
+		let bitmap = BITMAP.lock();
+		let start = tsc::now_ns();
+		for _ in 0..ROUNDS {
+			black_box(bitmap.next_available_linear(0).ok());
+		}
+		let linear = (tsc::now_ns() - start) / ROUNDS;
+		let start = tsc::now_ns();
+		for _ in 0..ROUNDS {
+			black_box(bitmap.next_available_summary(0).ok());
+		}
+		let summary = (tsc::now_ns() - start) / ROUNDS;
+		println!(
+			"search from 0: linear {} ns, summary {} ns",
+			linear, summary
+		);
+	}
+
+	let mut bitmap = BITMAP.lock();
+	for frame in frames {
+		bitmap.free_frame(frame).unwrap();
+	}
+	println!();
+}
+
+/// ## Cow_test
+/// Clone a space with one written page, the frame stays shared until a write. \
+/// The first write copies the frame, the second one takes the last reference.
+pub fn cow_test() {
+	const PAGE: usize = USER_START;
+	if !virtualmemory::is_paging_enabled() {
+		println!("cow test needs paging");
+		return;
+	}
+	let read = |frame: u64| {
+		with_frame(frame, |page| unsafe {
+			(page as *const u32).read_volatile()
+		})
+	};
+	let write = |value: u32| unsafe { (PAGE as *mut u32).write_volatile(value) };
+
+	let mut parent = AddressSpace::new().unwrap();
+	let frame = BITMAP.lock().alloc_frame().unwrap();
+	parent
+		.map_page(PAGE, frame as u64, PageFlags::KERNEL)
+		.unwrap();
+	parent.activate();
+	write(0x1111);
+	let child = parent.clone_cow().unwrap();
+	let (parent_frame, child_frame) = (
+		parent.translate(PAGE).unwrap(),
+		child.translate(PAGE).unwrap(),
+	);
+	let refcount = BITMAP.lock().refcount(frame);
+	println!(
+		"after clone:  parent 0x{:08x}, child 0x{:08x}, refcount {}, {:?}",
+		parent_frame,
+		child_frame,
+		refcount,
+		parent.query(PAGE).unwrap().1
+	);
+	let mut ok = parent_frame == child_frame && refcount == 2;
+
+	// the parent gets a copy, the child keeps the frame and the old value
+	write(0x2222);
+	let (parent_frame, child_frame) = (
+		parent.translate(PAGE).unwrap(),
+		child.translate(PAGE).unwrap(),
+	);
+	println!(
+		"parent write: parent 0x{:08x} = 0x{:x}, child 0x{:08x} = 0x{:x}",
+		parent_frame,
+		read(parent_frame),
+		child_frame,
+		read(child_frame)
+	);
+	ok &=
+		parent_frame != child_frame && read(parent_frame) == 0x2222 && read(child_frame) == 0x1111;
+
+	// last reference, the child writes in place
+	child.activate();
+	write(0x3333);
+	let after = child.translate(PAGE).unwrap();
+	println!("child write:  child 0x{:08x} = 0x{:x}", after, read(after));
+	ok &= after == child_frame && read(after) == 0x3333;
+
+	addressspace::activate_kernel();
+	drop(child);
+	drop(parent);
+	println!("cow test: {}", if ok { "ok" } else { "failed" });
+}
+
+/// ## Shm_test
+/// Map one shared memory object in two spaces, a write in one is read in the other
+/// and in a copy on write clone of it. \
+/// The frame is freed once the object is unlinked and the last mapping is gone.
+pub fn shm_test() {
+	if !virtualmemory::is_paging_enabled() {
+		println!("shm test needs paging");
+		return;
+	}
+	let read = |address: usize| unsafe { (address as *const u32).read_volatile() };
+	let write = |address: usize, value: u32| unsafe { (address as *mut u32).write_volatile(value) };
+
+	shm::create("shmtest", 0x2000).unwrap();
+	let mut first = AddressSpace::new().unwrap();
+	let mut second = AddressSpace::new().unwrap();
+	let a = shm::map("shmtest", &mut first, None, PageFlags::KERNEL).unwrap();
+	let b = shm::map("shmtest", &mut second, Some(USER_START), PageFlags::KERNEL).unwrap();
+	let frame = first.translate(a).unwrap();
+	let info = shm::objects().into_iter().find(|o| o.name == "shmtest");
+	let info = info.unwrap();
+	println!(
+		"{} pages mapped at 0x{:08x} and 0x{:08x}, frame 0x{:08x}, {} mappings",
+		info.pages, a, b, frame, info.mappings
+	);
+	let mut ok = second.translate(b) == Some(frame) && info.mappings == 2;
+
+	first.activate();
+	write(a, 0x1111);
+	let mut clone = first.clone_cow().unwrap();
+	second.activate();
+	let seen = read(b);
+	write(b, 0x2222);
+	clone.activate();
+	let cloned = read(a);
+	println!(
+		"second reads 0x{:x}, the clone of first reads 0x{:x}",
+		seen, cloned
+	);
+	ok &= seen == 0x1111 && cloned == 0x2222;
+
+	addressspace::activate_kernel();
+	shm::unlink("shmtest").unwrap();
+	shm::unmap(&mut first, a, 0x2000).unwrap();
+	shm::unmap(&mut second, b, 0x2000).unwrap();
+	let before_last = BITMAP.lock().refcount(frame as usize);
+	shm::unmap(&mut clone, a, 0x2000).unwrap();
+	let freed = BITMAP.lock().is_address_free(frame as usize);
+	println!(
+		"unlinked and unmapped: refcount {} before the last, freed {}",
+		before_last, freed
+	);
+	ok &= before_last == 1 && freed;
+	drop(clone);
+	drop(second);
+	drop(first);
+	println!("shm test: {}", if ok { "ok" } else { "failed" });
+}
+
+/// ## Swap_test
+/// Touch a lazy user allocation bigger than the free memory, each page gets its
+/// number and is read back. \
+/// The first pages are swapped out on the way and come back from the disk.
+#[cfg(feature = "swap")]
+pub fn swap_test() {
+	if !virtualmemory::is_paging_enabled() {
+		println!("swap test needs paging");
+		return;
+	}
+	let before = SWAP.lock().stats();
+	if before.slots == before.used {
+		println!("swap test needs a swap device with free slots");
+		return;
+	}
+	let stats = physicalmemory::stats();
+	let free = stats.usable.saturating_sub(stats.used - stats.reserved);
+	let pages = free + (before.slots - before.used) / 2;
+	let layout = Layout::from_size_align(pages * 0x1000, 0x1000).unwrap();
+	let area = USER_ALLOCATOR.lock().allocate(layout);
+	if area.is_null() {
+		println!("swap test: no room for {} pages", pages);
+		return;
+	}
+	let page = |i: usize| unsafe { area.add(i * 0x1000) as *mut usize };
+	for i in 0..pages {
+		unsafe { page(i).write_volatile(i) };
+	}
+	let ok = (0..pages).all(|i| unsafe { page(i).read_volatile() } == i);
+	let after = SWAP.lock().stats();
+	println!(
+		"{} pages over {} free frames: {} swapped out, {} swapped in",
+		pages,
+		free,
+		after.swapped_out - before.swapped_out,
+		after.swapped_in - before.swapped_in
+	);
+	USER_ALLOCATOR.lock().deallocate(area, layout);
+	println!("swap test: {}", if ok { "ok" } else { "failed" });
+}
+
+#[cfg(not(feature = "swap"))]
+pub fn swap_test() {
+	println!("swap test needs the swap feature");
+}
@@ -0,0 +1,194 @@
+use crate::include::asm_utile::{cpuid, wrmsr};
+use crate::memory::pae::{self, PAE_DIRECTORY};
+use crate::memory::pageflags::PageFlags;
+use crate::memory::virtualmemory::{self, PAGE_DIRECTORY};
+use crate::memory::vrange::VirtualRanges;
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+// Kernel range of the device mappings, shared by every address space.
+pub const IOREMAP_START: usize = 0xE000_0000;
+pub const IOREMAP_END: usize = 0xF000_0000;
+
+const IA32_PAT: u32 = 0x277;
+// Power on value with entry 1 changed from write through to write combining.
+const PAT_VALUE: u64 = 0x0007_0406_0007_0106;
+
+// A guard page after each mapping.
+static IOREMAP: Mutex<VirtualRanges> =
+	Mutex::new(VirtualRanges::new(IOREMAP_START, IOREMAP_END, 1));
+static PAT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum IoremapError {
+	OutOfSpace,
+	OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+	// Every access goes to the device in order, for registers.
+	Uncached,
+	// Writes are merged and may be reordered, for framebuffers.
+	#[allow(unused)]
+	WriteCombining,
+}
+
+impl CacheMode {
+	/// Write combining is uncached without PAT.
+	fn flags(self) -> PageFlags {
+		match self {
+			CacheMode::WriteCombining if PAT.load(Ordering::Relaxed) => {
+				PageFlags::KERNEL | PageFlags::WRITE_THROUGH
+			}
+			_ => PageFlags::MMIO,
+		}
+	}
+}
+
+/// Set PAT entry 1 to write combining, called once paging is on.
+pub fn init() {
+	if cpuid(1).edx & (1 << 16) == 0 {
+		return;
+	}
+	// nothing is mapped with entry 1 yet, no cache flush needed
+	unsafe { wrmsr(IA32_PAT, PAT_VALUE) };
+	PAT.store(true, Ordering::Relaxed);
+}
+
+/// ## Mmio
+/// Device memory mapped by ```ioremap```, every access is volatile and checked
+/// against the size of the region. \
+/// The mapping stays until ```iounmap```.
+#[derive(Debug)]
+pub struct Mmio {
+	base: usize,
+	physical_address: u64,
+	size: usize,
+	mode: CacheMode,
+}
+
+#[allow(unused)]
+impl Mmio {
+	/// Virtual address of the first byte.
+	pub fn base(&self) -> usize {
+		self.base
+	}
+
+	pub fn physical_address(&self) -> u64 {
+		self.physical_address
+	}
+
+	pub fn size(&self) -> usize {
+		self.size
+	}
+
+	pub fn mode(&self) -> CacheMode {
+		self.mode
+	}
+
+	fn register<T>(&self, offset: usize) -> usize {
+		assert!(
+			offset + size_of::<T>() <= self.size,
+			"register 0x{:x} out of the region of 0x{:x} bytes",
+			offset,
+			self.size
+		);
+		assert!(
+			offset % size_of::<T>() == 0,
+			"register 0x{:x} is not aligned",
+			offset
+		);
+		self.base + offset
+	}
+
+	/// Read the register at `offset`, its width is the one of `T`.
+	pub fn read<T: Copy>(&self, offset: usize) -> T {
+		unsafe { read_volatile(self.register::<T>(offset) as *const T) }
+	}
+
+	pub fn write<T: Copy>(&self, offset: usize, value: T) {
+		unsafe { write_volatile(self.register::<T>(offset) as *mut T, value) }
+	}
+}
+
+fn map(
+	virtual_address: usize,
+	physical_address: u64,
+	flags: PageFlags,
+) -> Result<(), IoremapError> {
+	let result = if pae::is_enabled() {
+		PAE_DIRECTORY
+			.lock()
+			.map_page(virtual_address, physical_address, flags)
+	} else {
+		assert!(
+			physical_address >> 32 == 0,
+			"device memory over 4GB without PAE"
+		);
+		PAGE_DIRECTORY
+			.lock()
+			.map_page(virtual_address, physical_address as usize, flags)
+	};
+	result.map_err(|_| IoremapError::OutOfMemory)
+}
+
+/// Unmap the pages of `[start, end)`, their frames are left alone.
+fn unmap(start: usize, end: usize) {
+	let mut directory = PAGE_DIRECTORY.lock();
+	for address in (start..end).step_by(0x1000) {
+		directory.forget_page(address);
+	}
+}
+
+/// ## Ioremap
+/// Map `size` bytes of device memory at `physical_address` in the ioremap range
+/// with `mode`. \
+/// The frames are not taken from the allocator. Without paging the region is the
+/// physical memory itself.
+#[allow(unused)]
+pub fn ioremap(physical_address: u64, size: usize, mode: CacheMode) -> Result<Mmio, IoremapError> {
+	assert!(size != 0, "empty MMIO region");
+	let offset = (physical_address & 0xFFF) as usize;
+	if !virtualmemory::is_paging_enabled() {
+		return Ok(Mmio {
+			base: physical_address as usize,
+			physical_address,
+			size,
+			mode,
+		});
+	}
+	let pages = (offset + size).div_ceil(0x1000) * 0x1000;
+	let start = IOREMAP
+		.lock()
+		.alloc(pages, 0x1000)
+		.map_err(|_| IoremapError::OutOfSpace)?;
+	let frame = physical_address & !0xFFF;
+	for page in (0..pages).step_by(0x1000) {
+		if let Err(error) = map(start + page, frame + page as u64, mode.flags()) {
+			unmap(start, start + page);
+			IOREMAP.lock().free(start, pages).unwrap();
+			return Err(error);
+		}
+	}
+	Ok(Mmio {
+		base: start + offset,
+		physical_address,
+		size,
+		mode,
+	})
+}
+
+/// Unmap a region of ```ioremap``` and give its range back.
+#[allow(unused)]
+pub fn iounmap(region: Mmio) {
+	if !virtualmemory::is_paging_enabled() {
+		return;
+	}
+	let start = region.base & !0xFFF;
+	let pages = ((region.base & 0xFFF) + region.size).div_ceil(0x1000) * 0x1000;
+	unmap(start, start + pages);
+	IOREMAP.lock().free(start, pages).unwrap();
+}
@@ -1,4 +1,18 @@
+pub mod addressspace;
+pub mod demand;
 pub mod dynamicmemory;
 pub mod heap_test;
+pub mod ioremap;
+pub mod pae;
+pub mod pageflags;
+pub mod pagewalk;
 pub mod physicalmemory;
+#[cfg(feature = "frame-poison")]
+pub mod poison;
+pub mod region;
+pub mod shm;
+#[cfg(feature = "swap")]
+pub mod swap;
 pub mod virtualmemory;
+pub mod vmalloc;
+pub mod vrange;
@@ -0,0 +1,515 @@
+use crate::include::asm_utile::{cpuid, rdmsr, wrmsr};
+use crate::memory::addressspace;
+use crate::memory::pageflags::PageFlags;
+use crate::memory::physicalmemory::{PhysicalMemoryError, BITMAP, HIGH_MEMORY_START};
+use crate::memory::virtualmemory::{flush_page, VirtualMemoryError};
+use core::arch::asm;
+use core::ops::Range;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const ENTRIES: usize = 512;
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const LARGE_PAGE_SIZE: u64 = 0x20_0000;
+// The 4 page directories are set in the last 4 entries of the last one,
+// so page tables show up from 0xFF800000 and the directories from 0xFFFFC000.
+pub const RECURSIVE_BASE: usize = 0xFF80_0000;
+const DIRECTORIES_BASE: usize = 0xFFFF_C000;
+
+const EFER: u32 = 0xC000_0080;
+const EFER_NXE: u64 = 1 << 11;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NX: AtomicBool = AtomicBool::new(false);
+
+// CR3 holds a 32 bits address, the PDPT lives in the kernel image.
+#[repr(C, align(32))]
+struct PageDirectoryPointerTable([u64; 4]);
+
+static mut PDPT: PageDirectoryPointerTable = PageDirectoryPointerTable([0; 4]);
+
+type Table = [u64; ENTRIES];
+
+pub struct PaeDirectory {
+	directories: [usize; 4],
+	recursive: bool,
+	// Present pages in the table of each slot, see the 2 levels PageDirectory.
+	counts: [u16; 4 * ENTRIES],
+}
+
+impl PaeDirectory {
+	fn directory(&self, pdpi: usize) -> &'static mut Table {
+		let address = if self.recursive {
+			DIRECTORIES_BASE + pdpi * 0x1000
+		} else {
+			self.directories[pdpi]
+		};
+		unsafe { &mut *(address as *mut Table) }
+	}
+
+	fn table(&self, pdpi: usize, pdi: usize) -> &'static mut Table {
+		let address = if self.recursive {
+			RECURSIVE_BASE + (pdpi * ENTRIES + pdi) * 0x1000
+		} else {
+			(self.directory(pdpi)[pdi] & ADDRESS_MASK) as usize
+		};
+		unsafe { &mut *(address as *mut Table) }
+	}
+
+	fn indexes(virtual_address: usize) -> (usize, usize, usize) {
+		(
+			virtual_address >> 30,
+			(virtual_address >> 21) & 0x1FF,
+			(virtual_address >> 12) & 0x1FF,
+		)
+	}
+
+	/// Add a page table for `pdi` if there is none yet.
+	fn ensure_table(&mut self, pdpi: usize, pdi: usize) -> Result<(), PhysicalMemoryError> {
+		if self.directory(pdpi)[pdi] & 0x1 == 0 {
+			let page_table_add = BITMAP.lock().alloc_page_table()?;
+			self.directory(pdpi)[pdi] = page_table_add as u64 | PageFlags::KERNEL.bits();
+			self.table(pdpi, pdi).fill(0);
+			addressspace::share_kernel_entry(
+				(pdpi * ENTRIES + pdi) << 21,
+				self.directory(pdpi)[pdi],
+			);
+		}
+		Ok(())
+	}
+
+	/// Present and mapping a 2MB page instead of a page table.
+	fn is_large(&self, pdpi: usize, pdi: usize) -> bool {
+		let flags = PageFlags::from_entry(self.directory(pdpi)[pdi]);
+		flags.contains(PageFlags::PRESENT | PageFlags::LARGE)
+	}
+
+	/// A user page needs the user bit in its directory entry too,
+	/// in every space for a kernel table.
+	fn allow_user(&mut self, pdpi: usize, pdi: usize, flags: PageFlags) {
+		let entry = self.directory(pdpi)[pdi];
+		if flags.contains(PageFlags::USER) && entry & PageFlags::USER.bits() == 0 {
+			self.directory(pdpi)[pdi] = entry | PageFlags::USER.bits();
+			addressspace::share_kernel_entry(
+				(pdpi * ENTRIES + pdi) << 21,
+				entry | PageFlags::USER.bits(),
+			);
+		}
+	}
+
+	/// ```NO_EXECUTE``` is a reserved bit until NX is on.
+	fn supported(mut flags: PageFlags) -> PageFlags {
+		if !NX.load(Ordering::Relaxed) {
+			flags.remove(PageFlags::NO_EXECUTE);
+		}
+		flags
+	}
+
+	/// ## Map_page
+	/// Same as the 2 levels ```map_page``` with a 64 bits physical address, so frames
+	/// above 4GB and ```PageFlags::NO_EXECUTE``` can be used.
+	pub fn map_page(
+		&mut self,
+		virtual_address: usize,
+		physical_address: u64,
+		flags: PageFlags,
+	) -> Result<(), PhysicalMemoryError> {
+		assert!(virtual_address & 0xFFF == 0, "Address is not 4KB aligned");
+		assert!(physical_address & 0xFFF == 0, "Address is not 4KB aligned");
+		assert!(
+			virtual_address < RECURSIVE_BASE,
+			"over 0xFF800000 is reserved"
+		);
+		let flags = Self::supported(flags);
+		let (pdpi, pdi, pti) = Self::indexes(virtual_address);
+		assert!(
+			!self.is_large(pdpi, pdi),
+			"inside a large page. address: 0x{:x}",
+			virtual_address
+		);
+
+		self.ensure_table(pdpi, pdi)?;
+		self.allow_user(pdpi, pdi, flags);
+		let entry = &mut self.table(pdpi, pdi)[pti];
+		assert!(
+			*entry & 0x1 == 0,
+			"page entry already present. address: 0x{:x}, pti: {}",
+			virtual_address,
+			pti
+		);
+		*entry = physical_address | flags.bits();
+		self.counts[pdpi * ENTRIES + pdi] += 1;
+		flush_page(virtual_address);
+		Ok(())
+	}
+
+	/// Remove the mapping and drop its reference on the frame.
+	#[track_caller]
+	pub fn unmap_page(&mut self, virtual_address: usize) -> Result<(), PhysicalMemoryError> {
+		let physical_address = self.forget_page(virtual_address);
+		if physical_address >= HIGH_MEMORY_START {
+			BITMAP.lock().free_high_frame(physical_address)?;
+		} else {
+			BITMAP.lock().put_frame(physical_address as usize)?;
+		}
+		Ok(())
+	}
+
+	/// Remove the mapping but leave the frame alone, return its physical address.
+	#[track_caller]
+	pub fn forget_page(&mut self, virtual_address: usize) -> u64 {
+		let (pdpi, pdi, pti) = Self::indexes(virtual_address);
+		assert!(
+			self.directory(pdpi)[pdi] & 0x1 != 0,
+			"Directory entry not preset. virtual address: 0x{:x}",
+			virtual_address
+		);
+		assert!(
+			!self.is_large(pdpi, pdi),
+			"inside a large page, use unmap_large_page. virtual address: 0x{:x}",
+			virtual_address
+		);
+		let entry = &mut self.table(pdpi, pdi)[pti];
+		assert!(
+			*entry & 0x1 != 0,
+			"page entry not present. virtual address: 0x{:x}, pti: {}",
+			virtual_address,
+			pti
+		);
+		let physical_address = *entry & ADDRESS_MASK;
+		*entry = 0;
+		flush_page(virtual_address);
+		let slot = pdpi * ENTRIES + pdi;
+		self.counts[slot] -= 1;
+		if self.counts[slot] == 0 {
+			self.release_table(pdpi, pdi);
+		}
+		physical_address
+	}
+
+	/// Same as the 2 levels ```replace_entry```.
+	pub fn replace_entry(&mut self, virtual_address: usize, entry: u64) -> u64 {
+		let (pdpi, pdi, pti) = Self::indexes(virtual_address);
+		assert!(
+			self.directory(pdpi)[pdi] & 0x1 != 0 && !self.is_large(pdpi, pdi),
+			"no page table. virtual address: 0x{:x}",
+			virtual_address
+		);
+		let old = core::mem::replace(&mut self.table(pdpi, pdi)[pti], entry);
+		flush_page(virtual_address);
+		let slot = pdpi * ENTRIES + pdi;
+		match (old != 0, entry != 0) {
+			(false, true) => self.counts[slot] += 1,
+			(true, false) => {
+				self.counts[slot] -= 1;
+				if self.counts[slot] == 0 {
+					self.release_table(pdpi, pdi);
+				}
+			}
+			_ => {}
+		}
+		old
+	}
+
+	/// Free the empty page table of `pdi` and clear its directory entry,
+	/// in every space for a kernel table.
+	fn release_table(&mut self, pdpi: usize, pdi: usize) {
+		let (table, view) = (
+			(self.directory(pdpi)[pdi] & ADDRESS_MASK) as usize,
+			self.table(pdpi, pdi) as *mut Table as usize,
+		);
+		self.directory(pdpi)[pdi] = 0;
+		if self.recursive {
+			flush_page(view);
+		}
+		BITMAP.lock().free_page_table(table).unwrap();
+		addressspace::share_kernel_entry((pdpi * ENTRIES + pdi) << 21, 0);
+	}
+
+	/// Same as the 2 levels ```recount```.
+	pub fn recount(&mut self, range: Range<usize>) {
+		for slot in range.start >> 21..range.end >> 21 {
+			let (pdpi, pdi) = (slot / ENTRIES, slot % ENTRIES);
+			let entry = self.directory(pdpi)[pdi];
+			self.counts[slot] = if entry & 0x1 != 0 && !self.is_large(pdpi, pdi) {
+				self.table(pdpi, pdi).iter().filter(|e| **e != 0).count() as u16
+			} else {
+				0
+			};
+		}
+	}
+
+	pub fn translate(&self, virtual_address: usize) -> u64 {
+		let (pdpi, pdi, pti) = Self::indexes(virtual_address);
+		assert!(
+			self.directory(pdpi)[pdi] & 0x1 != 0,
+			"Directory entry not preset. virtual address: 0x{:x}",
+			virtual_address
+		);
+		if self.is_large(pdpi, pdi) {
+			let base = self.directory(pdpi)[pdi] & ADDRESS_MASK;
+			return base + (virtual_address as u64 & (LARGE_PAGE_SIZE - 1));
+		}
+		let entry = self.table(pdpi, pdi)[pti];
+		assert!(
+			entry & 0x1 != 0,
+			"page entry not present. virtual address: 0x{:x}, pti: {}",
+			virtual_address,
+			pti
+		);
+		entry & ADDRESS_MASK
+	}
+
+	/// Follow a CR3 switch, `directories` are the ones of the new PDPT.
+	pub fn set_directories(&mut self, directories: [usize; 4]) {
+		self.directories = directories;
+	}
+
+	pub fn for_each_mapping(&self, start: usize, end: usize, mut f: impl FnMut(usize, u64)) {
+		let end = end.min(RECURSIVE_BASE);
+		if start >= end {
+			return;
+		}
+		for table in (start >> 21)..=((end - 1) >> 21) {
+			let (pdpi, pdi) = (table / ENTRIES, table % ENTRIES);
+			if self.directory(pdpi)[pdi] & 0x1 == 0 {
+				continue;
+			}
+			if self.is_large(pdpi, pdi) {
+				let base = self.directory(pdpi)[pdi] & ADDRESS_MASK;
+				for pti in 0..ENTRIES {
+					let virtual_address = table << 21 | pti << 12;
+					if start <= virtual_address && virtual_address < end {
+						f(virtual_address, base + (pti << 12) as u64);
+					}
+				}
+				continue;
+			}
+			for (pti, entry) in self.table(pdpi, pdi).iter().enumerate() {
+				let virtual_address = table << 21 | pti << 12;
+				if entry & 0x1 != 0 && start <= virtual_address && virtual_address < end {
+					f(virtual_address, entry & ADDRESS_MASK);
+				}
+			}
+		}
+	}
+
+	/// The 4 directories and every page table, the recursive entries are skipped.
+	pub fn for_each_page_table(&self, mut f: impl FnMut(u64)) {
+		for pdpi in 0..4 {
+			f(self.directories[pdpi] as u64);
+			let entries = if pdpi == 3 { ENTRIES - 4 } else { ENTRIES };
+			for entry in self.directory(pdpi)[..entries].iter() {
+				let flags = PageFlags::from_entry(*entry);
+				if flags.contains(PageFlags::PRESENT) && !flags.contains(PageFlags::LARGE) {
+					f(entry & ADDRESS_MASK);
+				}
+			}
+		}
+	}
+
+	pub fn query(&self, virtual_address: usize) -> Option<(u64, PageFlags)> {
+		let (pdpi, pdi, pti) = Self::indexes(virtual_address);
+		let directory_entry = self.directory(pdpi)[pdi];
+		if directory_entry & 0x1 == 0 {
+			return None;
+		}
+		if self.is_large(pdpi, pdi) {
+			let frame = (directory_entry & ADDRESS_MASK) + (pti << 12) as u64;
+			return Some((frame, PageFlags::from_entry(directory_entry)));
+		}
+		let entry = self.table(pdpi, pdi)[pti];
+		(entry & 0x1 != 0).then(|| (entry & ADDRESS_MASK, PageFlags::from_entry(entry)))
+	}
+
+	pub fn walk(&self, virtual_address: usize) -> (u64, Option<u64>) {
+		let (pdpi, pdi, pti) = Self::indexes(virtual_address);
+		let directory_entry = self.directory(pdpi)[pdi];
+		if directory_entry & 0x1 == 0 || self.is_large(pdpi, pdi) {
+			return (directory_entry, None);
+		}
+		(directory_entry, Some(self.table(pdpi, pdi)[pti]))
+	}
+
+	pub fn protect(
+		&mut self,
+		range: Range<usize>,
+		flags: PageFlags,
+	) -> Result<(), VirtualMemoryError> {
+		let flags = Self::supported(flags);
+		let pages = (range.start & !0xFFF..range.end).step_by(0x1000);
+		if pages.clone().any(|address| self.query(address).is_none()) {
+			return Err(VirtualMemoryError::NotMapped);
+		}
+		for address in pages {
+			let (pdpi, pdi, pti) = Self::indexes(address);
+			if self.is_large(pdpi, pdi) {
+				let entry = &mut self.directory(pdpi)[pdi];
+				*entry =
+					*entry & ADDRESS_MASK | PageFlags::from_entry(*entry).protected(flags).bits();
+				addressspace::share_kernel_entry(address, *entry);
+				flush_page(address);
+				continue;
+			}
+			self.allow_user(pdpi, pdi, flags);
+			let entry = &mut self.table(pdpi, pdi)[pti];
+			*entry = *entry & ADDRESS_MASK | PageFlags::from_entry(*entry).protected(flags).bits();
+			flush_page(address);
+		}
+		Ok(())
+	}
+
+	/// ## Map_large_page
+	/// Same as the 2 levels ```map_large_page``` with 2MB pages.
+	pub fn map_large_page(
+		&mut self,
+		virtual_address: usize,
+		physical_address: u64,
+		flags: PageFlags,
+	) -> Result<(), VirtualMemoryError> {
+		assert!(
+			virtual_address as u64 & (LARGE_PAGE_SIZE - 1) == 0
+				&& physical_address & (LARGE_PAGE_SIZE - 1) == 0,
+			"Address is not 2MB aligned"
+		);
+		assert!(
+			virtual_address < RECURSIVE_BASE,
+			"over 0xFF800000 is reserved"
+		);
+		let flags = Self::supported(flags);
+		let (pdpi, pdi, _) = Self::indexes(virtual_address);
+
+		let entry = self.directory(pdpi)[pdi];
+		if entry & 0x1 != 0 {
+			assert!(
+				!self.is_large(pdpi, pdi),
+				"large page already present. address: 0x{:x}",
+				virtual_address
+			);
+			if self.counts[pdpi * ENTRIES + pdi] != 0 {
+				return Err(VirtualMemoryError::AlreadyMapped);
+			}
+			BITMAP
+				.lock()
+				.free_page_table((entry & ADDRESS_MASK) as usize)
+				.unwrap();
+			if self.recursive {
+				// the recursive view of the freed table
+				flush_page(self.table(pdpi, pdi) as *mut Table as usize);
+			}
+		}
+		let entry = physical_address | (flags | PageFlags::LARGE).bits();
+		self.directory(pdpi)[pdi] = entry;
+		addressspace::share_kernel_entry(virtual_address, entry);
+		flush_page(virtual_address);
+		Ok(())
+	}
+
+	/// Remove the large page at `virtual_address` and drop the reference of each
+	/// of its frames.
+	#[track_caller]
+	pub fn unmap_large_page(&mut self, virtual_address: usize) -> Result<(), PhysicalMemoryError> {
+		let (pdpi, pdi, _) = Self::indexes(virtual_address);
+		assert!(
+			self.is_large(pdpi, pdi),
+			"no large page. virtual address: 0x{:x}",
+			virtual_address
+		);
+		let base = self.directory(pdpi)[pdi] & ADDRESS_MASK;
+		{
+			let mut bitmap = BITMAP.lock();
+			for frame in (base..base + LARGE_PAGE_SIZE).step_by(0x1000) {
+				if frame >= HIGH_MEMORY_START {
+					bitmap.free_high_frame(frame)?;
+				} else {
+					bitmap.put_frame(frame as usize)?;
+				}
+			}
+		}
+		self.directory(pdpi)[pdi] = 0;
+		addressspace::share_kernel_entry(virtual_address, 0);
+		flush_page(virtual_address);
+		Ok(())
+	}
+
+	pub fn init_directory(&mut self, start_addr: usize, end_addr: usize) {
+		for address in (start_addr..=end_addr).step_by(0x200000) {
+			let (pdpi, pdi, _) = Self::indexes(address);
+			self.ensure_table(pdpi, pdi).unwrap();
+		}
+		let (pdpi, pdi, _) = Self::indexes(end_addr);
+		self.ensure_table(pdpi, pdi).unwrap();
+	}
+}
+
+pub static PAE_DIRECTORY: Mutex<PaeDirectory> = Mutex::new(PaeDirectory {
+	directories: [0; 4],
+	recursive: false,
+	counts: [0; 4 * ENTRIES],
+});
+
+/// Physical address of the boot PDPT, the root of the kernel address space.
+pub fn pdpt_address() -> usize {
+	addr_of_mut!(PDPT) as usize
+}
+
+pub fn is_enabled() -> bool {
+	ENABLED.load(Ordering::Relaxed)
+}
+
+/// True once the NX bit is turned on, ```PageFlags::NO_EXECUTE``` is dropped otherwise.
+pub fn nx_enabled() -> bool {
+	NX.load(Ordering::Relaxed)
+}
+
+pub fn is_supported() -> bool {
+	cpuid(1).edx & (1 << 6) != 0
+}
+
+fn nx_supported() -> bool {
+	cpuid(0x8000_0000).eax >= 0x8000_0001 && cpuid(0x8000_0001).edx & (1 << 20) != 0
+}
+
+/// ## Setup
+/// Allocate the 4 page directories and route ```PAGE_DIRECTORY``` to the PAE tables. \
+/// Paging is still off, the tables are written through their physical address.
+pub fn setup() {
+	let mut directory = PAE_DIRECTORY.lock();
+	for pdpi in 0..4 {
+		let address = BITMAP.lock().alloc_page_table().unwrap();
+		directory.directories[pdpi] = address;
+		directory.directory(pdpi).fill(0);
+		// only the present bit is allowed in a PDPT entry
+		unsafe { (*addr_of_mut!(PDPT)).0[pdpi] = address as u64 | PageFlags::PRESENT.bits() };
+	}
+	ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// ## Enable
+/// Add the recursive entries, turn on NX if the cpu has it and start paging.
+pub fn enable() {
+	let mut directory = PAE_DIRECTORY.lock();
+	for pdpi in 0..4 {
+		let address = directory.directories[pdpi] as u64;
+		directory.directory(3)[ENTRIES - 4 + pdpi] = address | PageFlags::KERNEL.bits();
+	}
+	if nx_supported() {
+		unsafe { wrmsr(EFER, rdmsr(EFER) | EFER_NXE) };
+		NX.store(true, Ordering::Relaxed);
+	}
+	unsafe {
+		asm!(
+			"mov {tmp}, cr4",
+			"or {tmp}, 0x20",
+			"mov cr4, {tmp}",
+			"mov cr3, {pdpt}",
+			"mov {tmp}, cr0",
+			"or {tmp}, 0x80010000",
+			"mov cr0, {tmp}",
+			pdpt = in(reg) addr_of_mut!(PDPT) as usize,
+			tmp = out(reg) _,
+		);
+	}
+	directory.recursive = true;
+}
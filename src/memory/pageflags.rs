@@ -0,0 +1,142 @@
+use core::fmt;
+use core::ops::{BitAnd, BitOr, BitOrAssign, Not};
+
+/// ## PageFlags
+/// Flag bits of a page table or page directory entry. \
+/// ```NO_EXECUTE``` only exists in PAE entries, it is dropped without it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PageFlags(u64);
+
+#[allow(unused)]
+impl PageFlags {
+	pub const PRESENT: PageFlags = PageFlags(1 << 0);
+	pub const WRITABLE: PageFlags = PageFlags(1 << 1);
+	pub const USER: PageFlags = PageFlags(1 << 2);
+	// Alone it selects PAT entry 1, write combining once ioremap::init ran.
+	pub const WRITE_THROUGH: PageFlags = PageFlags(1 << 3);
+	pub const CACHE_DISABLE: PageFlags = PageFlags(1 << 4);
+	pub const ACCESSED: PageFlags = PageFlags(1 << 5);
+	pub const DIRTY: PageFlags = PageFlags(1 << 6);
+	// Page size bit of a directory entry, a 4MB page or 2MB with PAE.
+	pub const LARGE: PageFlags = PageFlags(1 << 7);
+	pub const GLOBAL: PageFlags = PageFlags(1 << 8);
+	pub const NO_EXECUTE: PageFlags = PageFlags(1 << 63);
+	// Available bit, a shared page made read only until its first write.
+	pub const COW: PageFlags = PageFlags(1 << 9);
+	// Available bit, a page of a shared memory object, never made copy on write.
+	pub const SHARED: PageFlags = PageFlags(1 << 11);
+
+	pub const KERNEL: PageFlags = PageFlags(Self::PRESENT.0 | Self::WRITABLE.0);
+	pub const USER_RW: PageFlags = PageFlags(Self::KERNEL.0 | Self::USER.0);
+	pub const MMIO: PageFlags =
+		PageFlags(Self::KERNEL.0 | Self::WRITE_THROUGH.0 | Self::CACHE_DISABLE.0);
+
+	// Left as they are by ```protect```.
+	pub const STATUS: PageFlags = PageFlags(
+		Self::PRESENT.0 | Self::ACCESSED.0 | Self::DIRTY.0 | Self::LARGE.0 | Self::SHARED.0,
+	);
+
+	const ALL: u64 = 0x3FF | 1 << 11 | 1 << 63;
+	const NAMES: [(PageFlags, &'static str); 12] = [
+		(Self::PRESENT, "present"),
+		(Self::WRITABLE, "writable"),
+		(Self::USER, "user"),
+		(Self::WRITE_THROUGH, "write-through"),
+		(Self::CACHE_DISABLE, "cache-disable"),
+		(Self::ACCESSED, "accessed"),
+		(Self::DIRTY, "dirty"),
+		(Self::LARGE, "large"),
+		(Self::GLOBAL, "global"),
+		(Self::NO_EXECUTE, "no-execute"),
+		(Self::COW, "cow"),
+		(Self::SHARED, "shared"),
+	];
+
+	pub const fn empty() -> PageFlags {
+		PageFlags(0)
+	}
+
+	pub const fn bits(self) -> u64 {
+		self.0
+	}
+
+	/// Flags of a raw entry, the address and unknown bits are dropped.
+	pub const fn from_entry(entry: u64) -> PageFlags {
+		PageFlags(entry & Self::ALL)
+	}
+
+	pub const fn contains(self, other: PageFlags) -> bool {
+		self.0 & other.0 == other.0
+	}
+
+	pub const fn intersects(self, other: PageFlags) -> bool {
+		self.0 & other.0 != 0
+	}
+
+	pub fn insert(&mut self, other: PageFlags) {
+		self.0 |= other.0;
+	}
+
+	pub fn remove(&mut self, other: PageFlags) {
+		self.0 &= !other.0;
+	}
+
+	/// ## Protected
+	/// Flags of an entry once ```protect``` sets `flags`, the ```STATUS``` bits are kept. \
+	/// A ```COW``` page asked to be writable stays read only with the marker,
+	/// the write fault gives it a frame of its own.
+	pub fn protected(self, flags: PageFlags) -> PageFlags {
+		let mut new = (self & Self::STATUS) | (flags & !(Self::STATUS | Self::COW));
+		if self.contains(Self::COW) && flags.contains(Self::WRITABLE) {
+			new.remove(Self::WRITABLE);
+			new.insert(Self::COW);
+		}
+		new
+	}
+}
+
+impl BitOr for PageFlags {
+	type Output = PageFlags;
+
+	fn bitor(self, rhs: PageFlags) -> PageFlags {
+		PageFlags(self.0 | rhs.0)
+	}
+}
+
+impl BitOrAssign for PageFlags {
+	fn bitor_assign(&mut self, rhs: PageFlags) {
+		self.0 |= rhs.0;
+	}
+}
+
+impl BitAnd for PageFlags {
+	type Output = PageFlags;
+
+	fn bitand(self, rhs: PageFlags) -> PageFlags {
+		PageFlags(self.0 & rhs.0)
+	}
+}
+
+impl Not for PageFlags {
+	type Output = PageFlags;
+
+	fn not(self) -> PageFlags {
+		PageFlags(!self.0 & Self::ALL)
+	}
+}
+
+impl fmt::Debug for PageFlags {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut first = true;
+		for (flag, name) in Self::NAMES {
+			if self.contains(flag) {
+				write!(f, "{}{}", if first { "" } else { " | " }, name)?;
+				first = false;
+			}
+		}
+		if first {
+			write!(f, "empty")?;
+		}
+		Ok(())
+	}
+}
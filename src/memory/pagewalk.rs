@@ -0,0 +1,99 @@
+use crate::memory::pae;
+use crate::memory::pageflags::PageFlags;
+use crate::memory::virtualmemory::{self, PAGE_DIRECTORY};
+
+/// Virtual range `[start, end)` mapped to contiguous frames from `physical_address`.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+	pub start: usize,
+	pub end: usize,
+	pub physical_address: u64,
+	pub flags: PageFlags,
+}
+
+/// ## Mappings
+/// Walk of the active page directory, consecutive pages with contiguous frames
+/// and the same flags come as one ```Mapping```. \
+/// The directory is locked for each range only, a change in between shows up
+/// in the next ones.
+pub struct Mappings {
+	address: usize,
+	end: usize,
+}
+
+/// Every mapping of the active space below the recursive page tables.
+pub fn mappings() -> Mappings {
+	let end = if pae::is_enabled() {
+		pae::RECURSIVE_BASE
+	} else {
+		virtualmemory::RECURSIVE_BASE
+	};
+	Mappings { address: 0, end }
+}
+
+impl Iterator for Mappings {
+	type Item = Mapping;
+
+	fn next(&mut self) -> Option<Mapping> {
+		let directory = PAGE_DIRECTORY.lock();
+		let directory_size = virtualmemory::large_page_size();
+		let mut current: Option<Mapping> = None;
+		while self.address < self.end {
+			let size = match directory.walk(self.address).1 {
+				// a missing table or a large page, up to the next directory entry
+				None => directory_size - (self.address & (directory_size - 1)),
+				Some(_) => 0x1000,
+			};
+			let Some((frame, flags)) = directory.query(self.address) else {
+				self.address += size;
+				if current.is_some() {
+					return current;
+				}
+				continue;
+			};
+			// set by the CPU on access, they would split every range
+			let flags = flags & !(PageFlags::ACCESSED | PageFlags::DIRTY);
+			match &mut current {
+				Some(mapping)
+					if mapping.flags == flags
+						&& mapping.physical_address + (self.address - mapping.start) as u64
+							== frame =>
+				{
+					mapping.end += size
+				}
+				Some(_) => return current,
+				None => {
+					current = Some(Mapping {
+						start: self.address,
+						end: self.address + size,
+						physical_address: frame,
+						flags,
+					})
+				}
+			}
+			self.address += size;
+		}
+		current
+	}
+}
+
+/// ## Translation
+/// Every step of the walk for one virtual address, the raw entries included. \
+/// `page` is None when the address is not mapped.
+#[derive(Debug)]
+pub struct Translation {
+	pub directory_entry: u64,
+	pub table_entry: Option<u64>,
+	pub page: Option<(u64, PageFlags)>,
+}
+
+/// Walk the active page directory for `virtual_address`, it never panics.
+pub fn translate(virtual_address: usize) -> Translation {
+	let directory = PAGE_DIRECTORY.lock();
+	let (directory_entry, table_entry) = directory.walk(virtual_address);
+	Translation {
+		directory_entry,
+		table_entry,
+		page: directory.query(virtual_address),
+	}
+}
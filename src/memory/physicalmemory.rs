@@ -1,6 +1,14 @@
 use crate::include::multiboot;
 use crate::include::symbols;
+#[cfg(feature = "frame-poison")]
+use crate::memory::poison;
+use crate::memory::region::{self, RegionKind, REGIONS};
+#[cfg(feature = "swap")]
+use crate::memory::swap;
 use crate::memory::virtualmemory::PDA;
+#[cfg(feature = "frame-poison")]
+use core::panic::Location;
+use core::ptr::null_mut;
 use spin::Mutex;
 
 #[derive(Debug)]
@@ -9,29 +17,125 @@ pub enum PhysicalMemoryError {
 	NoFrameAvailable,
 	FrameAlreadyUse,
 	FrameNotInUse,
+	InvalidAlignment,
+	FrameReserved,
 }
 
 pub const N_FRAMES: usize = 1048576;
+// Frames above 4GB are only reachable with PAE, tracked up to 8GB.
+pub const HIGH_MEMORY_START: u64 = 0x1_0000_0000;
+const MAX_HIGH_FRAMES: usize = 1048576;
+// Count of the frames in use before the allocator, never freed by put_frame.
+const RESERVED: u8 = u8::MAX;
+// References saturate one under it, such a frame is never freed either.
+const MAX_REFS: u8 = RESERVED - 1;
+#[allow(unused)]
+pub const ISA_DMA_LIMIT: usize = 0x100_0000; // ISA DMA only reaches the first 16MB
 const BITMAP_LEN: usize = N_FRAMES / 32;
 
+/// ## MemoryStats
+/// Frame counts kept up to date by the bitmap, no scan needed. \
+/// `total`, `usable`, `reserved` and `kernel` are fixed at boot,
+/// heap usage is kept by each ```HeapAllocator```.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+	pub total: usize,
+	pub usable: usize,
+	pub reserved: usize,
+	pub kernel: usize,
+	pub page_tables: usize,
+	pub used: usize,
+	// Free frames above 4GB, only PAE can map them, apart from usable and used.
+	pub high: usize,
+}
+
+impl MemoryStats {
+	const fn new() -> Self {
+		MemoryStats {
+			total: 0,
+			usable: 0,
+			reserved: 0,
+			kernel: 0,
+			page_tables: 0,
+			used: 0,
+			high: 0,
+		}
+	}
+}
+
+// One bit per bitmap word, set when the 32 frames of the word are all used.
+const SUMMARY_LEN: usize = BITMAP_LEN / 32;
+// One bit per summary word, set when the 1024 frames under it are all used.
+const TOP_LEN: usize = SUMMARY_LEN / 32;
+
 #[repr(align(4096))]
 pub struct PhysicalMemory {
 	pub bitmap: [u32; BITMAP_LEN],
+	summary: [u32; SUMMARY_LEN],
+	top: [u32; TOP_LEN],
 	next: usize,
+	pub stats: MemoryStats,
+	// One count per frame up to the end of usable memory under 4GB,
+	// in frames taken under the kernel image at init.
+	refcounts: *mut u8,
+	refcounts_len: usize,
+	// Bitmap of the frames from 4GB, set when used or not usable. Same placement.
+	high_bitmap: *mut u32,
+	high_len: usize,
+	high_next: usize,
+}
+
+unsafe impl Send for PhysicalMemory {}
+
+/// First clear bit of `word` at or after bit `from` (bit 0 is the MSB, as in the bitmap).
+fn first_clear(word: u32, from: usize) -> Option<usize> {
+	let free = !word & (u32::MAX >> from);
+	(free != 0).then(|| free.leading_zeros() as usize)
 }
 
 #[allow(unused)]
 impl PhysicalMemory {
+	/// ## Next_available
+	/// Find a free frame starting from the last allocated or freed word, wrapping around
+	/// to the start of memory. \
+	/// The summary levels let it skip 1024 used frames per bit.
 	fn next_available(&self) -> Result<usize, PhysicalMemoryError> {
+		self.next_available_summary(self.next)
+			.or_else(|_| self.next_available_summary(0))
+	}
+
+	/// Index of the first bitmap word at or after `start` with a free frame.
+	fn find_free_word(&self, start: usize) -> Option<usize> {
+		let summary = start / 32;
+		if let Some(bit) = first_clear(self.summary[summary], start % 32) {
+			return Some(summary * 32 + bit);
+		}
+		let summary = summary + 1;
+		if summary >= SUMMARY_LEN {
+			return None;
+		}
+		let top = summary / 32;
+		let summary = match first_clear(self.top[top], summary % 32) {
+			Some(bit) => top * 32 + bit,
+			None => {
+				let top = (top + 1..TOP_LEN).find(|&top| self.top[top] != u32::MAX)?;
+				top * 32 + (!self.top[top]).leading_zeros() as usize
+			}
+		};
+		Some(summary * 32 + (!self.summary[summary]).leading_zeros() as usize)
+	}
+
+	/// Former search, a linear scan of the bitmap from word `start`. Kept for the benchmark.
+	pub fn next_available_linear(&self, start: usize) -> Result<usize, PhysicalMemoryError> {
 		let idx = self
 			.bitmap
 			.iter()
-			.skip(self.next)
+			.skip(start)
 			.position(|&x| x != 0xFFFFFFFF);
 
 		idx.map_or(Err(PhysicalMemoryError::NoFrameAvailable), |i| {
 			let mut j: usize = 0;
-			let real_idx = i + self.next;
+			let real_idx = i + start;
 			while !self.bitmap[real_idx] & (0x80000000 >> j) == 0 {
 				j += 1;
 			}
@@ -39,6 +143,30 @@ impl PhysicalMemory {
 		})
 	}
 
+	/// Summary search from word `start` without wraparound, exposed for the benchmark.
+	pub fn next_available_summary(&self, start: usize) -> Result<usize, PhysicalMemoryError> {
+		self.find_free_word(start)
+			.map(|index| (index * 32 + (!self.bitmap[index]).leading_zeros() as usize) * 0x1000)
+			.ok_or(PhysicalMemoryError::NoFrameAvailable)
+	}
+
+	fn set_bit(&mut self, index: usize, offset: usize) {
+		self.bitmap[index] |= 0x80000000 >> offset;
+		if self.bitmap[index] == u32::MAX {
+			self.summary[index / 32] |= 0x80000000 >> (index % 32);
+			if self.summary[index / 32] == u32::MAX {
+				self.top[index / 1024] |= 0x80000000 >> (index / 32 % 32);
+			}
+		}
+	}
+
+	fn clear_bit(&mut self, index: usize, offset: usize) {
+		self.bitmap[index] &= !(0x80000000 >> offset);
+		self.summary[index / 32] &= !(0x80000000 >> (index % 32));
+		self.top[index / 1024] &= !(0x80000000 >> (index / 32 % 32));
+	}
+
+	#[track_caller]
 	fn alloc_bitmap(&mut self, address: usize) -> Result<(), PhysicalMemoryError> {
 		assert!(address % 0x1000 == 0, "Address is not 4KB aligned");
 		let index = address / 0x1000 / 0x20;
@@ -50,14 +178,22 @@ impl PhysicalMemory {
 
 		match self.bitmap[index] & (0x80000000 >> offset) == 0 {
 			true => {
-				self.bitmap[index] |= 0x80000000 >> offset;
+				self.set_bit(index, offset);
 				self.next = index;
+				self.stats.used += 1;
+				self.set_refcount(address / 0x1000, 1);
+				#[cfg(feature = "frame-poison")]
+				poison::on_alloc(address, Location::caller());
 				Ok(())
 			}
 			false => Err(PhysicalMemoryError::FrameAlreadyUse),
 		}
 	}
 
+	/// ## Free_frame
+	/// With the `frame-poison` feature, the frame is filled with a pattern checked
+	/// on its next allocation, and a double free prints both free sites.
+	#[track_caller]
 	pub fn free_frame(&mut self, address: usize) -> Result<(), PhysicalMemoryError> {
 		assert!(address % 0x1000 == 0, "Address is not 4KB aligned");
 		let index = address / 0x1000 / 0x20;
@@ -65,14 +201,207 @@ impl PhysicalMemory {
 
 		match self.bitmap[index] & (0x80000000 >> offset) != 0 {
 			true => {
-				self.bitmap[index] &= !(0x80000000 >> offset);
+				self.clear_bit(index, offset);
 				self.next = index;
+				self.stats.used -= 1;
+				self.set_refcount(address / 0x1000, 0);
+				#[cfg(feature = "frame-poison")]
+				poison::on_free(address, Location::caller());
 				Ok(())
 			}
-			false => Err(PhysicalMemoryError::FrameNotInUse),
+			false => {
+				#[cfg(feature = "frame-poison")]
+				poison::on_double_free(address, Location::caller());
+				Err(PhysicalMemoryError::FrameNotInUse)
+			}
+		}
+	}
+
+	fn set_refcount(&mut self, frame: usize, count: u8) {
+		if frame < self.refcounts_len {
+			unsafe { *self.refcounts.add(frame) = count };
+		}
+	}
+
+	/// Number of references to the frame at `address`, 0 if free. \
+	/// Frames reserved at boot or outside the tracked memory (ex. MMIO) count as 255.
+	pub fn refcount(&self, address: usize) -> usize {
+		let frame = address / 0x1000;
+		if frame < self.refcounts_len {
+			unsafe { *self.refcounts.add(frame) as usize }
+		} else if self.is_frame_free(frame) {
+			0
+		} else {
+			RESERVED as usize
+		}
+	}
+
+	/// ## Get_frame
+	/// Take an other reference on an allocated frame, ex) to map it twice. \
+	/// The count saturates at 254, such a frame is never freed.
+	pub fn get_frame(&mut self, address: usize) -> Result<(), PhysicalMemoryError> {
+		assert!(address % 0x1000 == 0, "Address is not 4KB aligned");
+		let frame = address / 0x1000;
+		if frame >= self.refcounts_len {
+			return Err(PhysicalMemoryError::OutofMemory);
+		}
+		match self.refcount(address) {
+			0 => Err(PhysicalMemoryError::FrameNotInUse),
+			count if count >= MAX_REFS as usize => Ok(()),
+			count => {
+				self.set_refcount(frame, count as u8 + 1);
+				Ok(())
+			}
+		}
+	}
+
+	/// ## Put_frame
+	/// Drop a reference, the frame is freed with the last one. \
+	/// Return true if the frame was freed. \
+	/// Only frames handed out by the allocator are counted, the others
+	/// (reserved at boot, MMIO) give ```FrameReserved```.
+	#[track_caller]
+	pub fn put_frame(&mut self, address: usize) -> Result<bool, PhysicalMemoryError> {
+		assert!(address % 0x1000 == 0, "Address is not 4KB aligned");
+		if address / 0x1000 >= self.refcounts_len {
+			return Err(PhysicalMemoryError::FrameReserved);
+		}
+		match self.refcount(address) {
+			// free_frame reports the double free
+			0 | 1 => self.free_frame(address).map(|_| true),
+			count if count == RESERVED as usize => Err(PhysicalMemoryError::FrameReserved),
+			count if count == MAX_REFS as usize => Ok(false),
+			count => {
+				self.set_refcount(address / 0x1000, count as u8 - 1);
+				Ok(false)
+			}
+		}
+	}
+
+	/// Physical ranges of the reference counts, the high memory bitmap and
+	/// the free sites of `frame-poison`, to identity map them with paging.
+	pub fn metadata_regions(&self) -> [(usize, usize); 3] {
+		let refcounts = self.refcounts as usize;
+		let high_bitmap = self.high_bitmap as usize;
+		#[cfg(feature = "frame-poison")]
+		let free_sites = poison::region();
+		#[cfg(not(feature = "frame-poison"))]
+		let free_sites = (0, 0);
+		[
+			(
+				refcounts,
+				refcounts + self.refcounts_len.div_ceil(0x1000) * 0x1000,
+			),
+			(
+				high_bitmap,
+				high_bitmap + (self.high_len / 8).div_ceil(0x1000) * 0x1000,
+			),
+			free_sites,
+		]
+	}
+
+	/// Frames under the kernel image for the boot time arrays, the only place
+	/// identity mapping can't collide with the heaps.
+	fn alloc_metadata(&mut self, bytes: usize) -> usize {
+		self.alloc_frames(
+			bytes.div_ceil(0x1000).max(1),
+			0x1000,
+			symbols::get_kernel_start() as usize,
+		)
+		.expect("no room under the kernel for physical memory metadata")
+	}
+
+	/// Mark the frames in `[start, end)` under 4GB as used if they are free, at init.
+	fn mark_used(&mut self, start: u64, end: u64) {
+		let end = end.min(HIGH_MEMORY_START);
+		let mut address = start & !0xFFF;
+		while address < end {
+			if self.is_address_free(address as usize) {
+				self.alloc_frame_address(address as usize).unwrap();
+			}
+			address += 0x1000;
+		}
+	}
+
+	/// Allocate the reference counts for `len` frames, every frame already in use
+	/// is reserved.
+	fn init_refcounts(&mut self, len: usize) {
+		self.refcounts = self.alloc_metadata(len) as *mut u8;
+		self.refcounts_len = len;
+		for frame in 0..len {
+			let count = if self.is_frame_free(frame) {
+				0
+			} else {
+				RESERVED
+			};
+			self.set_refcount(frame, count);
+		}
+	}
+
+	/// Allocate the high memory bitmap for `len` frames from 4GB, all marked as used.
+	fn init_high_bitmap(&mut self, len: usize) {
+		let len = len.min(MAX_HIGH_FRAMES).next_multiple_of(32);
+		if len == 0 {
+			return;
+		}
+		self.high_bitmap = self.alloc_metadata(len / 8) as *mut u32;
+		self.high_len = len;
+		for index in 0..len / 32 {
+			unsafe { *self.high_bitmap.add(index) = u32::MAX };
+		}
+	}
+
+	fn set_high_bit(&mut self, frame: usize, used: bool) {
+		let word = unsafe { &mut *self.high_bitmap.add(frame / 32) };
+		if used {
+			*word |= 0x80000000 >> (frame % 32);
+		} else {
+			*word &= !(0x80000000 >> (frame % 32));
 		}
 	}
 
+	/// Mark a usable range above 4GB as free, at init.
+	fn add_high_memory(&mut self, start: u64, end: u64) {
+		let start = (start.max(HIGH_MEMORY_START) - HIGH_MEMORY_START).div_ceil(0x1000) as usize;
+		let end = (((end - HIGH_MEMORY_START) / 0x1000) as usize).min(self.high_len);
+		for frame in start..end {
+			self.set_high_bit(frame, false);
+			self.stats.high += 1;
+		}
+	}
+
+	/// ## Alloc_high_frame
+	/// Allocate a frame above 4GB, only mappable with PAE. \
+	/// Return its 64 bits physical address.
+	pub fn alloc_high_frame(&mut self) -> Result<u64, PhysicalMemoryError> {
+		let words = self.high_len / 32;
+		let index = (0..words)
+			.map(|i| (self.high_next + i) % words)
+			.find(|&index| unsafe { *self.high_bitmap.add(index) } != u32::MAX)
+			.ok_or(PhysicalMemoryError::NoFrameAvailable)?;
+		let frame = index * 32 + unsafe { !*self.high_bitmap.add(index) }.leading_zeros() as usize;
+		self.set_high_bit(frame, true);
+		self.high_next = index;
+		self.stats.high -= 1;
+		Ok(HIGH_MEMORY_START + frame as u64 * 0x1000)
+	}
+
+	pub fn free_high_frame(&mut self, address: u64) -> Result<(), PhysicalMemoryError> {
+		assert!(address % 0x1000 == 0, "Address is not 4KB aligned");
+		let frame = address
+			.checked_sub(HIGH_MEMORY_START)
+			.map(|offset| (offset / 0x1000) as usize)
+			.filter(|&frame| frame < self.high_len)
+			.ok_or(PhysicalMemoryError::OutofMemory)?;
+		if unsafe { *self.high_bitmap.add(frame / 32) } & (0x80000000 >> (frame % 32)) == 0 {
+			return Err(PhysicalMemoryError::FrameNotInUse);
+		}
+		self.set_high_bit(frame, false);
+		self.high_next = frame / 32;
+		self.stats.high += 1;
+		Ok(())
+	}
+
 	pub fn is_frame_free(&self, frame: usize) -> bool {
 		let index = frame / 0x20;
 		let offset = frame % 0x20;
@@ -92,15 +421,85 @@ impl PhysicalMemory {
 	/// Change the bitmap status the very next available frame. \
 	/// Return with first frame address ```usize```. \
 	/// Can panic with ```PhysicalMemoryError```
+	#[track_caller]
 	pub fn alloc_frame(&mut self) -> Result<usize, PhysicalMemoryError> {
 		let next = self.next_available()?;
 		self.alloc_bitmap(next)?;
 		Ok(next)
 	}
 
+	/// Same as ```alloc_frame```, counted as page table in ```MemoryStats```.
+	#[track_caller]
+	pub fn alloc_page_table(&mut self) -> Result<usize, PhysicalMemoryError> {
+		let address = self.alloc_frame()?;
+		self.stats.page_tables += 1;
+		Ok(address)
+	}
+
+	/// Free a frame from ```alloc_page_table```.
+	#[track_caller]
+	pub fn free_page_table(&mut self, address: usize) -> Result<(), PhysicalMemoryError> {
+		self.free_frame(address)?;
+		self.stats.page_tables -= 1;
+		Ok(())
+	}
+
+	/// ## Alloc_frames
+	/// Allocate `count` physically contiguous frames. \
+	/// The first frame address is a multiple of `align` (power of two, at least 4KB)
+	/// and the whole range ends below `max_addr`, ex) 16MB for ISA DMA. \
+	/// Free them with ```free_frames```.
+	#[track_caller]
+	pub fn alloc_frames(
+		&mut self,
+		count: usize,
+		align: usize,
+		max_addr: usize,
+	) -> Result<usize, PhysicalMemoryError> {
+		assert!(count > 0, "Can not allocate 0 frame");
+		if !align.is_power_of_two() || align < 0x1000 {
+			return Err(PhysicalMemoryError::InvalidAlignment);
+		}
+		let step = align / 0x1000;
+		let end = (max_addr / 0x1000).min(self.stats.total).min(N_FRAMES);
+
+		let mut frame = 0;
+		while frame + count <= end {
+			// skip full words at once
+			let index = match self.find_free_word(frame / 32) {
+				Some(index) => index,
+				None => break,
+			};
+			if index * 32 > frame {
+				frame = (index * 32).next_multiple_of(step);
+				continue;
+			}
+			match (frame..frame + count).find(|&f| !self.is_frame_free(f)) {
+				Some(used) => frame = (used + 1).next_multiple_of(step),
+				None => {
+					for f in frame..frame + count {
+						self.alloc_bitmap(f * 0x1000)?;
+					}
+					return Ok(frame * 0x1000);
+				}
+			}
+		}
+		Err(PhysicalMemoryError::NoFrameAvailable)
+	}
+
+	/// Free `count` frames from `address`, as given by ```alloc_frames```.
+	#[track_caller]
+	pub fn free_frames(&mut self, address: usize, count: usize) -> Result<(), PhysicalMemoryError> {
+		for i in 0..count {
+			self.free_frame(address + i * 0x1000)?;
+		}
+		Ok(())
+	}
+
 	/// ## Alloc_frame_address
 	/// Change the bitmap status with physical address. \
 	/// No return but can panic.
+	#[track_caller]
 	pub fn alloc_frame_address(&mut self, address: usize) -> Result<(), PhysicalMemoryError> {
 		self.alloc_bitmap(address)
 	}
@@ -108,71 +507,127 @@ impl PhysicalMemory {
 
 pub static BITMAP: Mutex<PhysicalMemory> = Mutex::new(PhysicalMemory {
 	bitmap: [0; BITMAP_LEN],
+	summary: [0; SUMMARY_LEN],
+	top: [0; TOP_LEN],
 	next: 0,
+	stats: MemoryStats::new(),
+	refcounts: null_mut(),
+	refcounts_len: 0,
+	high_bitmap: null_mut(),
+	high_len: 0,
+	high_next: 0,
 });
 
+/// ## Alloc_frame_or_evict
+/// Same as ```alloc_frame```, with the `swap` feature a cold user page is swapped
+/// out when no frame is left. \
+/// Don't call it with ```LAZY_REGIONS``` or ```PAGE_DIRECTORY``` locked.
+pub fn alloc_frame_or_evict() -> Result<usize, PhysicalMemoryError> {
+	let result = BITMAP.lock().alloc_frame();
+	#[cfg(feature = "swap")]
+	if let Err(PhysicalMemoryError::NoFrameAvailable) = result {
+		if swap::evict().is_ok() {
+			return BITMAP.lock().alloc_frame();
+		}
+	}
+	result
+}
+
+pub fn stats() -> MemoryStats {
+	BITMAP.lock().stats
+}
+
 /// ## Init physical memory
-/// Take Multiboot memorymap and mark unuseable memory in bitmap. \
-/// Mark the space of already take by kernel. ex) gdt, vga, ps2, etc...
+/// Take the sanitized memory map of ```region``` and mark everything but usable memory
+/// in bitmap, holes included. \
+/// Reserve the space of already take by kernel. ex) gdt, vga, modules, etc... \
+/// The addresses are 64 bits, usable memory above 4GB goes to the high memory bitmap.
 pub fn init(multiboot_info: usize) {
-	unsafe {
-		let memory_map = multiboot::parse_multiboot_info(multiboot_info, 6).unwrap();
-		let map = memory_map as *const multiboot::MultibootMemoryMapTag;
-		let tag_size = (*map).size;
-		let entry_size = (*map).entry_size as isize;
-
-		let mut entry = map.offset(1) as *const multiboot::MultibootMemoryMapEntry;
-		let entry_end = map as *const _ as usize + tag_size as usize;
-
-		while (entry as usize) < entry_end {
-			// crate::println!(
-			// 	"memory type: {}, bass_addr : 0x{:x},  length : 0x{:x}",
-			// 	(*entry)._type,
-			// 	(*entry).base_addr,
-			// 	(*entry).length
-			// );
-			if (*entry)._type == 0 || (*entry)._type == 8 {
-				break;
-			}
-			if (*entry)._type != 1 {
-				let mut count = 0;
-				let base_addr = (*entry).base_addr & !0xFFF;
-				let end_addr = (*entry).base_addr + (*entry).length;
-
-				while base_addr + count < end_addr {
-					BITMAP
-						.lock()
-						.alloc_frame_address((base_addr + count) as usize)
-						.unwrap();
-					count += 0x1000;
+	region::init(multiboot_info);
+
+	let mut usable_end = 0;
+	let mut high_end = HIGH_MEMORY_START;
+	{
+		let map = REGIONS.lock();
+		let mut bitmap = BITMAP.lock();
+		let mut cursor = 0;
+		for region in map.regions() {
+			if region.kind == RegionKind::Usable {
+				bitmap.mark_used(cursor, region.start);
+				let start_frame = region.start.div_ceil(0x1000) as usize;
+				let end_frame = (region.end / 0x1000) as usize;
+				bitmap.stats.total = bitmap.stats.total.max(end_frame);
+				if region.start < HIGH_MEMORY_START {
+					let end_frame = end_frame.min(N_FRAMES);
+					bitmap.stats.usable += end_frame.saturating_sub(start_frame);
+					usable_end = usable_end.max(end_frame);
+				}
+				high_end = high_end.max(region.end);
+			} else {
+				// usable once reclaimed, the frames need a count
+				if region.kind == RegionKind::AcpiReclaimable && region.end <= HIGH_MEMORY_START {
+					usable_end = usable_end.max((region.end / 0x1000) as usize);
 				}
+				bitmap.mark_used(cursor, region.end);
 			}
-			entry = entry.offset(
-				entry_size / core::mem::size_of::<multiboot::MultibootMemoryMapEntry>() as isize,
-			);
+			cursor = region.end;
 		}
+		let end_frame = (cursor.div_ceil(0x1000) as usize).min(N_FRAMES);
+		bitmap.stats.total = bitmap.stats.total.max(end_frame);
+	}
 
-		BITMAP.lock().alloc_frame_address(0x0).unwrap();
-		BITMAP.lock().alloc_frame_address(0xb8000).unwrap();
+	let reserved;
+	{
+		let mut map = REGIONS.lock();
+		map.claim("ivt, bda, gdt", 0x0, 0x1000).unwrap();
+		map.claim("vga, bios", 0xA0000, 0x100000).unwrap();
+		reserved = BITMAP.lock().stats.used;
+		BITMAP.lock().stats.reserved = reserved;
 
-		let mut kernel_start = symbols::get_kernel_start() as usize & !0xFFF;
-		let kernel_end = symbols::get_kernel_end() as usize & !0xFFF;
+		let kernel_start = symbols::get_kernel_start() as usize & !0xFFF;
+		let kernel_end = (symbols::get_kernel_end() as usize & !0xFFF) + 0x1000;
 		// crate::println!("[PHYSICAL] kernel alloc: 0x{:08x}, 0x{:08x}", kernel_start, kernel_end);
-		while kernel_start <= kernel_end {
-			BITMAP.lock().alloc_frame_address(kernel_start).unwrap();
-			kernel_start += 0x1000;
-		}
+		map.claim("kernel image", kernel_start, kernel_end).unwrap();
+		map.claim("page directory", PDA, PDA + 0x1000).unwrap();
 
-		let mapping_start = PDA;
-		BITMAP.lock().alloc_frame_address(mapping_start).unwrap();
+		// GRUB can put these inside a range reserved above, keep the first one then
+		let _ = map.claim(
+			"multiboot info",
+			multiboot_info,
+			multiboot_info + multiboot::info_size(multiboot_info),
+		);
+		multiboot::for_each_module(multiboot_info, |start, end| {
+			let _ = map.claim("module", start, end);
+		});
+	}
 
-		let multiboot_info_address = multiboot_info & !0xFFF;
-		if BITMAP.lock().is_address_free(multiboot_info_address) {
-			BITMAP
-				.lock()
-				.alloc_frame_address(multiboot_info_address)
-				.unwrap();
+	let metadata_regions = {
+		let mut bitmap = BITMAP.lock();
+		// taken first, reserved with the other boot time arrays
+		#[cfg(feature = "frame-poison")]
+		{
+			let table = bitmap.alloc_metadata(poison::table_size(usable_end));
+			poison::init(table, usable_end);
+		}
+		bitmap.init_refcounts(usable_end);
+		bitmap.init_high_bitmap(((high_end - HIGH_MEMORY_START) / 0x1000) as usize);
+		bitmap.stats.kernel = bitmap.stats.used - reserved;
+		bitmap.metadata_regions()
+	};
+	let mut map = REGIONS.lock();
+	for ((start, end), name) in
+		metadata_regions
+			.into_iter()
+			.zip(["frame refcounts", "high memory bitmap", "free sites"])
+	{
+		if start != end {
+			map.record(name, start, end).unwrap();
+		}
+	}
+	for region in map.regions() {
+		if region.kind == RegionKind::Usable && region.end > HIGH_MEMORY_START {
+			BITMAP.lock().add_high_memory(region.start, region.end);
 		}
-		// crate::println!("multiboot alloc: 0x{:x}", multiboot_info_address);
 	}
+	// crate::println!("multiboot alloc: 0x{:x}", multiboot_info_address);
 }
@@ -0,0 +1,163 @@
+use crate::memory::physicalmemory::N_FRAMES;
+use crate::memory::virtualmemory::with_frame;
+use crate::println;
+use core::panic::Location;
+use core::ptr::null_mut;
+use spin::Mutex;
+
+// Every word of a poisoned frame holds the pattern.
+const POISON: u32 = 0xDEAD_BEEF;
+const WORDS: usize = 0x1000 / 4;
+// Distinct free sites, there are a few dozen calls to the free functions.
+const MAX_SITES: usize = 1024;
+
+type Site = &'static Location<'static>;
+
+// Set for frames poisoned by a free, frames that were never freed are not checked.
+static POISONED: Mutex<[u32; N_FRAMES / 32]> = Mutex::new([0; N_FRAMES / 32]);
+
+/// ## FreeSites
+/// Last free site of each frame, out of the frame so a write after free can't erase it. \
+/// A frame keeps the index of its site plus one, 0 for none. The indexes are
+/// in frames taken under the kernel image at init, like the reference counts.
+struct FreeSites {
+	sites: [Option<Site>; MAX_SITES],
+	count: usize,
+	indexes: *mut u16,
+	len: usize,
+}
+
+unsafe impl Send for FreeSites {}
+
+static FREE_SITES: Mutex<FreeSites> = Mutex::new(FreeSites {
+	sites: [None; MAX_SITES],
+	count: 0,
+	indexes: null_mut(),
+	len: 0,
+});
+
+impl FreeSites {
+	fn set(&mut self, frame: usize, site: Site) {
+		if frame >= self.len {
+			return;
+		}
+		let index = match self.sites[..self.count]
+			.iter()
+			.position(|s| s.is_some_and(|s| core::ptr::eq(s, site)))
+		{
+			Some(index) => index + 1,
+			None if self.count < MAX_SITES => {
+				self.sites[self.count] = Some(site);
+				self.count += 1;
+				self.count
+			}
+			None => 0,
+		};
+		unsafe { *self.indexes.add(frame) = index as u16 };
+	}
+
+	fn get(&self, frame: usize) -> Option<Site> {
+		if frame >= self.len {
+			return None;
+		}
+		match unsafe { *self.indexes.add(frame) } as usize {
+			0 => None,
+			index => self.sites[index - 1],
+		}
+	}
+}
+
+/// Bytes of the per frame indexes for `frames` frames.
+pub fn table_size(frames: usize) -> usize {
+	frames * 2
+}
+
+/// Take the indexes at physical address `table`, for `frames` frames, at init.
+pub fn init(table: usize, frames: usize) {
+	let mut sites = FREE_SITES.lock();
+	sites.indexes = table as *mut u16;
+	sites.len = frames;
+	unsafe { sites.indexes.write_bytes(0, frames) };
+}
+
+/// Physical range of the indexes, to identity map them with paging.
+pub fn region() -> (usize, usize) {
+	let sites = FREE_SITES.lock();
+	let start = sites.indexes as usize;
+	(
+		start,
+		start + table_size(sites.len).div_ceil(0x1000) * 0x1000,
+	)
+}
+
+fn take_poisoned(frame: usize) -> bool {
+	let mut poisoned = POISONED.lock();
+	let mask = 0x80000000 >> (frame % 32);
+	let was = poisoned[frame / 32] & mask != 0;
+	poisoned[frame / 32] &= !mask;
+	was
+}
+
+fn set_poisoned(frame: usize) {
+	POISONED.lock()[frame / 32] |= 0x80000000 >> (frame % 32);
+}
+
+fn free_site(frame: usize) -> Option<Site> {
+	FREE_SITES.lock().get(frame)
+}
+
+/// ## On_free
+/// Fill the freed frame with the poison pattern and keep `site` in the side table.
+pub fn on_free(address: usize, site: Site) {
+	if address / 0x1000 >= N_FRAMES {
+		return;
+	}
+	with_frame(address as u64, |frame| unsafe {
+		let words = frame as *mut u32;
+		for i in 0..WORDS {
+			words.add(i).write_volatile(POISON);
+		}
+	});
+	let frame = address / 0x1000;
+	FREE_SITES.lock().set(frame, site);
+	set_poisoned(frame);
+}
+
+/// ## On_alloc
+/// Check the pattern of a frame poisoned by ```on_free```, report a write after free
+/// with the free site and the allocation `site`.
+pub fn on_alloc(address: usize, site: Site) {
+	if address / 0x1000 >= N_FRAMES || !take_poisoned(address / 0x1000) {
+		return;
+	}
+	let corrupted = with_frame(address as u64, |frame| unsafe {
+		let words = frame as *const u32;
+		(0..WORDS).find(|&i| words.add(i).read_volatile() != POISON)
+	});
+	if let Some(word) = corrupted {
+		println!(
+			"[POISON] write after free in frame 0x{:08x} at offset 0x{:x}",
+			address,
+			word * 4
+		);
+		report("freed at", free_site(address / 0x1000));
+		report("allocated at", Some(site));
+	}
+}
+
+/// Report a second free of the frame at `address`.
+pub fn on_double_free(address: usize, site: Site) {
+	println!("[POISON] double free of frame 0x{:08x}", address);
+	let freed = (address / 0x1000 < N_FRAMES)
+		.then(|| free_site(address / 0x1000))
+		.flatten();
+	report("first freed at", freed);
+	report("freed again at", Some(site));
+}
+
+fn report(what: &str, site: Option<Site>) {
+	match site {
+		Some(site) => println!("    {} {}:{}", what, site.file(), site.line()),
+		None => println!("    {} unknown", what),
+	}
+}
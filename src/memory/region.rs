@@ -0,0 +1,292 @@
+use crate::include::multiboot;
+use crate::memory::physicalmemory::{BITMAP, HIGH_MEMORY_START};
+use spin::Mutex;
+
+const MAX_RAW_ENTRIES: usize = 64;
+const MAX_REGIONS: usize = 32;
+const MAX_RESERVATIONS: usize = 32;
+
+#[derive(Debug)]
+pub enum RegionError {
+	TooManyReservations,
+	Overlap,
+	InUse,
+	NotFound,
+}
+
+/// Firmware memory types, ordered so the most restrictive one wins where entries overlap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RegionKind {
+	Usable,
+	AcpiReclaimable,
+	AcpiNvs,
+	Reserved,
+	Bad,
+}
+
+impl RegionKind {
+	/// Unknown types, including the 0 some firmwares end the map with, are reserved.
+	fn from_multiboot(kind: u32) -> RegionKind {
+		match kind {
+			1 => RegionKind::Usable,
+			3 => RegionKind::AcpiReclaimable,
+			4 => RegionKind::AcpiNvs,
+			5 => RegionKind::Bad,
+			_ => RegionKind::Reserved,
+		}
+	}
+}
+
+/// Range `[start, end)` of the sanitized firmware map.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+	pub start: u64,
+	pub end: u64,
+	pub kind: RegionKind,
+}
+
+/// Named range taken out of the allocator, `[start, end)` page aligned.
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+	pub name: &'static str,
+	pub start: usize,
+	pub end: usize,
+}
+
+const EMPTY_REGION: Region = Region {
+	start: 0,
+	end: 0,
+	kind: RegionKind::Reserved,
+};
+
+const EMPTY_RESERVATION: Reservation = Reservation {
+	name: "",
+	start: 0,
+	end: 0,
+};
+
+pub struct RegionMap {
+	regions: [Region; MAX_REGIONS],
+	region_count: usize,
+	reservations: [Reservation; MAX_RESERVATIONS],
+	reservation_count: usize,
+}
+
+pub static REGIONS: Mutex<RegionMap> = Mutex::new(RegionMap {
+	regions: [EMPTY_REGION; MAX_REGIONS],
+	region_count: 0,
+	reservations: [EMPTY_RESERVATION; MAX_RESERVATIONS],
+	reservation_count: 0,
+});
+
+impl RegionMap {
+	/// Sorted, non overlapping regions. Addresses missing from the map are not usable.
+	pub fn regions(&self) -> &[Region] {
+		&self.regions[..self.region_count]
+	}
+
+	/// Reservations sorted by address.
+	pub fn reservations(&self) -> &[Reservation] {
+		&self.reservations[..self.reservation_count]
+	}
+
+	pub fn is_usable(&self, address: u64) -> bool {
+		self.regions()
+			.iter()
+			.any(|r| r.kind == RegionKind::Usable && r.start <= address && address < r.end)
+	}
+
+	fn push_region(&mut self, region: Region) {
+		match self.regions[..self.region_count].last_mut() {
+			Some(last) if last.end == region.start && last.kind == region.kind => {
+				last.end = region.end
+			}
+			_ if self.region_count < MAX_REGIONS => {
+				self.regions[self.region_count] = region;
+				self.region_count += 1;
+			}
+			_ => {}
+		}
+	}
+
+	/// ## Sanitize
+	/// Cut the raw entries at every start and end address, keep the most restrictive
+	/// type of each piece and merge neighbours of the same type.
+	fn sanitize(&mut self, raw: &[Region]) {
+		let mut bounds = [0u64; MAX_RAW_ENTRIES * 2];
+		let mut count = 0;
+		for region in raw {
+			bounds[count] = region.start;
+			bounds[count + 1] = region.end;
+			count += 2;
+		}
+		let bounds = &mut bounds[..count];
+		bounds.sort_unstable();
+
+		self.region_count = 0;
+		for pair in bounds.windows(2) {
+			let (start, end) = (pair[0], pair[1]);
+			if start == end {
+				continue;
+			}
+			let kind = raw
+				.iter()
+				.filter(|r| r.start <= start && end <= r.end)
+				.map(|r| r.kind)
+				.max();
+			if let Some(kind) = kind {
+				self.push_region(Region { start, end, kind });
+			}
+		}
+	}
+
+	fn overlaps(&self, start: usize, end: usize) -> bool {
+		self.reservations()
+			.iter()
+			.any(|r| r.start < end && start < r.end)
+	}
+
+	/// Add an entry for memory the allocator already handed out, ex) boot time arrays.
+	pub fn record(
+		&mut self,
+		name: &'static str,
+		start: usize,
+		end: usize,
+	) -> Result<(), RegionError> {
+		let start = start & !0xFFF;
+		let end = (end + 0xFFF) & !0xFFF;
+		if self.reservation_count == MAX_RESERVATIONS {
+			return Err(RegionError::TooManyReservations);
+		}
+		if self.overlaps(start, end) {
+			return Err(RegionError::Overlap);
+		}
+		let index = self
+			.reservations()
+			.iter()
+			.position(|r| r.start > start)
+			.unwrap_or(self.reservation_count);
+		self.reservations
+			.copy_within(index..self.reservation_count, index + 1);
+		self.reservations[index] = Reservation { name, start, end };
+		self.reservation_count += 1;
+		Ok(())
+	}
+
+	/// ## Claim
+	/// Reserve `[start, end)` under `name` and take its usable frames from the allocator. \
+	/// Fail if the range overlaps an other reservation or a frame is already in use.
+	pub fn claim(
+		&mut self,
+		name: &'static str,
+		start: usize,
+		end: usize,
+	) -> Result<(), RegionError> {
+		let start = start & !0xFFF;
+		let end = (end + 0xFFF) & !0xFFF;
+		if self.overlaps(start, end) {
+			return Err(RegionError::Overlap);
+		}
+		let mut bitmap = BITMAP.lock();
+		let mut usable = (start..end)
+			.step_by(0x1000)
+			.filter(|&address| self.is_usable(address as u64));
+		if usable.any(|address| !bitmap.is_address_free(address)) {
+			return Err(RegionError::InUse);
+		}
+		self.record(name, start, end)?;
+		for address in (start..end).step_by(0x1000) {
+			if self.is_usable(address as u64) {
+				bitmap.alloc_frame_address(address).unwrap();
+			}
+		}
+		Ok(())
+	}
+
+	/// Give back the usable frames of the reservation starting at `start`.
+	#[allow(unused)]
+	pub fn release(&mut self, start: usize) -> Result<(), RegionError> {
+		let index = self
+			.reservations()
+			.iter()
+			.position(|r| r.start == start)
+			.ok_or(RegionError::NotFound)?;
+		let reservation = self.reservations[index];
+		let mut bitmap = BITMAP.lock();
+		for address in (reservation.start..reservation.end).step_by(0x1000) {
+			if self.is_usable(address as u64) {
+				let _ = bitmap.free_frame(address);
+			}
+		}
+		self.reservations
+			.copy_within(index + 1..self.reservation_count, index);
+		self.reservation_count -= 1;
+		Ok(())
+	}
+
+	/// ## Reclaim_acpi
+	/// Turn the ACPI reclaimable regions under 4GB into usable memory. \
+	/// Only once the ACPI tables are parsed, ```acpi::init``` doesn't keep pointers to them.
+	/// Return the number of frames given back.
+	pub fn reclaim_acpi(&mut self) -> usize {
+		let mut frames = 0;
+		let mut bitmap = BITMAP.lock();
+		for region in self.regions[..self.region_count].iter_mut() {
+			if region.kind != RegionKind::AcpiReclaimable || region.end > HIGH_MEMORY_START {
+				continue;
+			}
+			region.kind = RegionKind::Usable;
+			// partial frames at the edges stay reserved
+			let start = region.start.div_ceil(0x1000) * 0x1000;
+			for address in (start..region.end & !0xFFF).step_by(0x1000) {
+				let address = address as usize;
+				let reserved = self.reservations[..self.reservation_count]
+					.iter()
+					.any(|r| r.start <= address && address < r.end);
+				if !reserved && bitmap.free_frame(address).is_ok() {
+					frames += 1;
+				}
+			}
+		}
+		bitmap.stats.reserved -= frames;
+		bitmap.stats.usable += frames;
+		drop(bitmap);
+
+		let regions = self.regions;
+		let count = self.region_count;
+		self.region_count = 0;
+		for region in &regions[..count] {
+			self.push_region(*region);
+		}
+		frames
+	}
+}
+
+/// ## Init regions
+/// Read and sanitize the Multiboot memory map. Entries are no longer cut at the
+/// first type 0 or 8, overlapping entries keep the most restrictive type.
+pub fn init(multiboot_info: usize) {
+	let mut raw = [EMPTY_REGION; MAX_RAW_ENTRIES];
+	let mut count = 0;
+	unsafe {
+		let memory_map = multiboot::parse_multiboot_info(multiboot_info, 6).unwrap();
+		let map = memory_map as *const multiboot::MultibootMemoryMapTag;
+		let entry_size = (*map).entry_size as usize;
+		let mut entry = map.add(1) as usize;
+		let entry_end = map as usize + (*map).size as usize;
+
+		while entry < entry_end && count < MAX_RAW_ENTRIES {
+			let e = &*(entry as *const multiboot::MultibootMemoryMapEntry);
+			if e.length != 0 {
+				raw[count] = Region {
+					start: e.base_addr,
+					end: e.base_addr.saturating_add(e.length),
+					kind: RegionKind::from_multiboot(e._type),
+				};
+				count += 1;
+			}
+			entry += entry_size;
+		}
+	}
+	REGIONS.lock().sanitize(&raw[..count]);
+}
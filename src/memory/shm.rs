@@ -0,0 +1,200 @@
+use crate::memory::addressspace::{AddressSpace, USER_END, USER_START};
+use crate::memory::pageflags::PageFlags;
+use crate::memory::physicalmemory::{self, BITMAP};
+use crate::memory::virtualmemory::with_frame;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// Automatic addresses are taken from the top of the private range.
+const AUTO_START: usize = 0x7000_0000;
+const MAX_NAME: usize = 32;
+
+#[derive(Debug)]
+pub enum ShmError {
+	NameTaken,
+	NameTooLong,
+	NotFound,
+	OutOfMemory,
+	OutOfSpace,
+	AlreadyMapped,
+	NotMapped,
+}
+
+/// ## SharedMemory
+/// Named frames mapped into any number of address spaces. \
+/// The object holds a reference on each frame and every mapping one more,
+/// a frame is freed with the last of them.
+struct SharedMemory {
+	name: String,
+	frames: Vec<u64>,
+}
+
+/// Name, size in pages and number of mappings of an object.
+#[derive(Debug, Clone)]
+pub struct ShmInfo {
+	pub name: String,
+	pub pages: usize,
+	pub mappings: usize,
+}
+
+// Objects still reachable by name, an unlinked one lives on in its mappings.
+static OBJECTS: Mutex<Vec<SharedMemory>> = Mutex::new(Vec::new());
+
+/// Give back the references of the object on `frames`.
+fn put_frames(frames: &[u64]) {
+	let mut bitmap = BITMAP.lock();
+	for frame in frames {
+		bitmap.put_frame(*frame as usize).unwrap();
+	}
+}
+
+/// ## Create
+/// New object of `size` bytes rounded up to whole pages, zeroed. \
+/// Not mapped anywhere until ```map```.
+#[allow(unused)]
+pub fn create(name: &str, size: usize) -> Result<(), ShmError> {
+	assert!(size != 0, "empty shared memory object");
+	if name.len() > MAX_NAME {
+		return Err(ShmError::NameTooLong);
+	}
+	let mut objects = OBJECTS.lock();
+	if objects.iter().any(|o| o.name == name) {
+		return Err(ShmError::NameTaken);
+	}
+	let pages = size.div_ceil(0x1000);
+	let mut frames = Vec::with_capacity(pages);
+	for _ in 0..pages {
+		let Ok(frame) = physicalmemory::alloc_frame_or_evict() else {
+			put_frames(&frames);
+			return Err(ShmError::OutOfMemory);
+		};
+		with_frame(frame as u64, |page| unsafe { page.write_bytes(0, 0x1000) });
+		frames.push(frame as u64);
+	}
+	objects.push(SharedMemory {
+		name: String::from(name),
+		frames,
+	});
+	Ok(())
+}
+
+/// First address of the automatic range where `pages` pages are free in `space`.
+fn find_free(space: &AddressSpace, pages: usize) -> Result<usize, ShmError> {
+	let mut start = AUTO_START;
+	for page in (AUTO_START..USER_END).step_by(0x1000) {
+		if page - start == pages * 0x1000 {
+			return Ok(start);
+		}
+		if space.query(page).is_some() {
+			start = page + 0x1000;
+		}
+	}
+	match USER_END - start >= pages * 0x1000 {
+		true => Ok(start),
+		false => Err(ShmError::OutOfSpace),
+	}
+}
+
+/// ## Map
+/// Map the object `name` in `space` at `address`, or at a free address of
+/// the top of the private range with None. \
+/// The pages take `flags` with ```PageFlags::SHARED```, so a clone of the space
+/// keeps writing to the same frames. \
+/// Return the address of the mapping.
+#[allow(unused)]
+pub fn map(
+	name: &str,
+	space: &mut AddressSpace,
+	address: Option<usize>,
+	flags: PageFlags,
+) -> Result<usize, ShmError> {
+	let objects = OBJECTS.lock();
+	let object = objects
+		.iter()
+		.find(|o| o.name == name)
+		.ok_or(ShmError::NotFound)?;
+	let size = object.frames.len() * 0x1000;
+	let start = match address {
+		Some(address) => {
+			assert!(address & 0xFFF == 0, "Address is not 4KB aligned");
+			if address < USER_START || address.checked_add(size).is_none_or(|end| end > USER_END) {
+				return Err(ShmError::OutOfSpace);
+			}
+			address
+		}
+		None => find_free(space, object.frames.len())?,
+	};
+	if (start..start + size)
+		.step_by(0x1000)
+		.any(|page| space.query(page).is_some())
+	{
+		return Err(ShmError::AlreadyMapped);
+	}
+	for (i, frame) in object.frames.iter().enumerate() {
+		BITMAP.lock().get_frame(*frame as usize).unwrap();
+		let page = start + i * 0x1000;
+		if space
+			.map_page(page, *frame, flags | PageFlags::SHARED)
+			.is_err()
+		{
+			BITMAP.lock().put_frame(*frame as usize).unwrap();
+			for mapped in (start..page).step_by(0x1000) {
+				space.unmap_page(mapped).unwrap();
+			}
+			return Err(ShmError::OutOfMemory);
+		}
+	}
+	Ok(start)
+}
+
+/// ## Unmap
+/// Remove the mapping of `size` bytes at `address` from `space`,
+/// as given by ```map```. \
+/// Nothing changes if one of the pages is not a shared memory page.
+#[allow(unused)]
+pub fn unmap(space: &mut AddressSpace, address: usize, size: usize) -> Result<(), ShmError> {
+	let pages = (address..address + size.div_ceil(0x1000) * 0x1000).step_by(0x1000);
+	let shared = |page| {
+		space
+			.query(page)
+			.is_some_and(|(_, flags)| flags.contains(PageFlags::SHARED))
+	};
+	if !pages.clone().all(shared) {
+		return Err(ShmError::NotMapped);
+	}
+	for page in pages {
+		space.unmap_page(page).unwrap();
+	}
+	Ok(())
+}
+
+/// ## Unlink
+/// Remove the name of the object, its frames are freed once the last
+/// mapping is gone.
+#[allow(unused)]
+pub fn unlink(name: &str) -> Result<(), ShmError> {
+	let mut objects = OBJECTS.lock();
+	let index = objects
+		.iter()
+		.position(|o| o.name == name)
+		.ok_or(ShmError::NotFound)?;
+	let object = objects.remove(index);
+	put_frames(&object.frames);
+	Ok(())
+}
+
+/// Every object still reachable by name.
+#[allow(unused)]
+pub fn objects() -> Vec<ShmInfo> {
+	OBJECTS
+		.lock()
+		.iter()
+		.map(|o| ShmInfo {
+			name: o.name.clone(),
+			pages: o.frames.len(),
+			// one reference is the one of the object
+			mappings: BITMAP.lock().refcount(o.frames[0] as usize) - 1,
+		})
+		.collect()
+}
@@ -0,0 +1,259 @@
+use crate::include::cmdline;
+use crate::io::ata::AtaDrive;
+use crate::io::block::{BlockDevice, RamDisk, SECTOR_SIZE};
+use crate::log;
+use crate::memory::demand::{LazyRegion, LAZY_REGIONS};
+use crate::memory::pageflags::PageFlags;
+use crate::memory::physicalmemory::BITMAP;
+use crate::memory::virtualmemory::{self, PageDirectory, PAGE_DIRECTORY};
+use core::slice;
+use spin::Mutex;
+
+// 256MB of swap at most, the slots are tracked in a fixed bitmap.
+const MAX_SLOTS: usize = 0x1_0000;
+const SECTORS_PER_SLOT: u64 = (0x1000 / SECTOR_SIZE) as u64;
+const RAMDISK_SIZE: usize = 8 * 1024 * 1024;
+
+// Available bit of a non present entry, the slot is in the address bits.
+const SWAPPED: u64 = 1 << 10;
+
+#[derive(Debug)]
+pub enum SwapError {
+	NoDevice,
+	Full,
+	NothingToEvict,
+	Io,
+}
+
+/// Swap usage, in pages.
+#[derive(Debug, Clone, Copy)]
+pub struct SwapStats {
+	pub device: &'static str,
+	pub slots: usize,
+	pub used: usize,
+	pub swapped_out: usize,
+	pub swapped_in: usize,
+}
+
+/// ## SwapArea
+/// Slots of one page on a block device and the hand of the clock
+/// going over the user lazy regions. \
+/// Lock it after ```LAZY_REGIONS``` and ```PAGE_DIRECTORY```.
+pub struct SwapArea {
+	device: Option<BlockDevice>,
+	slots: [u32; MAX_SLOTS / 32],
+	count: usize,
+	used: usize,
+	hand: usize,
+	swapped_out: usize,
+	swapped_in: usize,
+}
+
+pub static SWAP: Mutex<SwapArea> = Mutex::new(SwapArea {
+	device: None,
+	slots: [0; MAX_SLOTS / 32],
+	count: 0,
+	used: 0,
+	hand: 0,
+	swapped_out: 0,
+	swapped_in: 0,
+});
+
+/// Non present entry of a page written to `slot`.
+fn swap_entry(slot: usize) -> u64 {
+	(slot as u64) << 12 | SWAPPED
+}
+
+/// Slot of a swap entry, None for any other entry.
+fn slot_of(entry: u64) -> Option<usize> {
+	(entry & 0x1 == 0 && entry & SWAPPED != 0).then_some((entry >> 12) as usize)
+}
+
+impl SwapArea {
+	pub fn stats(&self) -> SwapStats {
+		SwapStats {
+			device: self.device.as_ref().map_or("none", |d| d.name()),
+			slots: self.count,
+			used: self.used,
+			swapped_out: self.swapped_out,
+			swapped_in: self.swapped_in,
+		}
+	}
+
+	fn alloc_slot(&mut self) -> Result<usize, SwapError> {
+		let slot = (0..self.count)
+			.find(|slot| self.slots[slot / 32] & (1 << (slot % 32)) == 0)
+			.ok_or(SwapError::Full)?;
+		self.slots[slot / 32] |= 1 << (slot % 32);
+		self.used += 1;
+		Ok(slot)
+	}
+
+	fn free_slot(&mut self, slot: usize) {
+		assert!(
+			self.slots[slot / 32] & (1 << (slot % 32)) != 0,
+			"swap slot {} is not in use",
+			slot
+		);
+		self.slots[slot / 32] &= !(1 << (slot % 32));
+		self.used -= 1;
+	}
+
+	/// ## Advance
+	/// Move the hand to the next page of a user region, back to the lowest one
+	/// after the last. \
+	/// Kernel regions are never swapped, the kernel may touch them anywhere.
+	fn advance(&mut self, regions: &[LazyRegion]) -> Option<usize> {
+		let user = regions.iter().filter(|r| r.flags.contains(PageFlags::USER));
+		let next = self.hand + 0x1000;
+		let page = user
+			.clone()
+			.filter(|r| next < r.end)
+			.map(|r| r.start.max(next))
+			.min()
+			.or_else(|| user.map(|r| r.start).min())?;
+		self.hand = page;
+		Some(page)
+	}
+}
+
+/// ## Init
+/// Pick the swap device from the ```swap``` option: ```ata``` for the master drive
+/// of the primary bus, ```off``` for none, a ram disk otherwise. \
+/// Must run before the heaps list the free frames, the ram disk takes some.
+pub fn init() {
+	if !virtualmemory::is_paging_enabled() {
+		return;
+	}
+	let device = match cmdline::option("swap") {
+		Some("off") => return,
+		Some("ata") => match AtaDrive::probe() {
+			Some(drive) => BlockDevice::Ata(drive),
+			None => {
+				log!("Swap: no ATA disk on the primary bus");
+				return;
+			}
+		},
+		_ => match RamDisk::new(RAMDISK_SIZE) {
+			Ok(disk) => BlockDevice::Ram(disk),
+			Err(e) => {
+				log!("Swap: no ram disk, {:?}", e);
+				return;
+			}
+		},
+	};
+	let mut swap = SWAP.lock();
+	swap.count = ((device.sectors() / SECTORS_PER_SLOT) as usize).min(MAX_SLOTS);
+	log!("Swap: {} of {} KiB", device.name(), swap.count * 4);
+	swap.device = Some(device);
+}
+
+/// ## Evict
+/// Second chance clock over the present pages of the user lazy regions. \
+/// A page accessed since the hand last passed loses its accessed bit and is kept,
+/// the first one that was not is written to a free slot and its frame freed. \
+/// Shared and copy on write pages are left alone.
+pub fn evict() -> Result<(), SwapError> {
+	let mut regions = LAZY_REGIONS.lock();
+	let mut directory = PAGE_DIRECTORY.lock();
+	let mut swap = SWAP.lock();
+	if swap.device.is_none() {
+		return Err(SwapError::NoDevice);
+	}
+	let slot = swap.alloc_slot()?;
+	let pages: usize = regions
+		.regions()
+		.iter()
+		.filter(|r| r.flags.contains(PageFlags::USER))
+		.map(|r| r.pages())
+		.sum();
+	// the first pass may only clear accessed bits
+	for _ in 0..2 * pages {
+		let Some(address) = swap.advance(regions.regions()) else {
+			break;
+		};
+		let Some((frame, flags)) = directory.query(address) else {
+			continue;
+		};
+		if flags.contains(PageFlags::COW) || BITMAP.lock().refcount(frame as usize) > 1 {
+			continue;
+		}
+		if flags.contains(PageFlags::ACCESSED) {
+			let entry = directory.walk(address).1.unwrap();
+			directory.replace_entry(address, entry & !PageFlags::ACCESSED.bits());
+			continue;
+		}
+		let page = unsafe { slice::from_raw_parts(address as *const u8, 0x1000) };
+		let sector = slot as u64 * SECTORS_PER_SLOT;
+		if swap.device.as_mut().unwrap().write(sector, page).is_err() {
+			swap.free_slot(slot);
+			return Err(SwapError::Io);
+		}
+		directory.replace_entry(address, swap_entry(slot));
+		BITMAP.lock().put_frame(frame as usize).unwrap();
+		regions.find(address).unwrap().committed -= 1;
+		swap.swapped_out += 1;
+		return Ok(());
+	}
+	swap.free_slot(slot);
+	Err(SwapError::NothingToEvict)
+}
+
+/// ## Swap_in
+/// Read the page at `address` back into `frame` and map it with `flags`
+/// if it was swapped out. \
+/// Return false when its entry is not a swap entry, the frame is left unused.
+pub fn swap_in(address: usize, frame: usize, flags: PageFlags) -> Result<bool, SwapError> {
+	let mut directory = PAGE_DIRECTORY.lock();
+	let Some(slot) = directory.walk(address).1.and_then(slot_of) else {
+		return Ok(false);
+	};
+	let mut swap = SWAP.lock();
+	directory.replace_entry(address, frame as u64 | flags.bits());
+	let page = unsafe { slice::from_raw_parts_mut(address as *mut u8, 0x1000) };
+	let sector = slot as u64 * SECTORS_PER_SLOT;
+	if swap.device.as_mut().unwrap().read(sector, page).is_err() {
+		// the page stays on the disk
+		directory.replace_entry(address, swap_entry(slot));
+		return Err(SwapError::Io);
+	}
+	swap.free_slot(slot);
+	swap.swapped_in += 1;
+	Ok(true)
+}
+
+/// ## Duplicate
+/// Copy the slot of the swap `entry` to a free one through `buffer`, for a clone
+/// of an address space. \
+/// Return the swap entry of the copy.
+pub fn duplicate(entry: u64, buffer: &mut [u8]) -> Result<u64, SwapError> {
+	let slot = slot_of(entry).expect("not a swap entry");
+	let mut swap = SWAP.lock();
+	let copy = swap.alloc_slot()?;
+	let device = swap.device.as_mut().unwrap();
+	let buffer = &mut buffer[..0x1000];
+	if device
+		.read(slot as u64 * SECTORS_PER_SLOT, buffer)
+		.and_then(|_| device.write(copy as u64 * SECTORS_PER_SLOT, buffer))
+		.is_err()
+	{
+		swap.free_slot(copy);
+		return Err(SwapError::Io);
+	}
+	Ok(swap_entry(copy))
+}
+
+/// Free the slot of `entry` if it is a swap entry, for a dropped address space.
+pub fn free_entry(entry: u64) {
+	if let Some(slot) = slot_of(entry) {
+		SWAP.lock().free_slot(slot);
+	}
+}
+
+/// Drop the slot of `address` if it was swapped out, when its region goes away.
+pub fn discard(directory: &mut PageDirectory, address: usize) {
+	if let Some(slot) = directory.walk(address).1.and_then(slot_of) {
+		directory.replace_entry(address, 0);
+		SWAP.lock().free_slot(slot);
+	}
+}
@@ -1,21 +1,50 @@
+use crate::include::asm_utile::cpuid;
+use crate::include::cmdline;
+use crate::include::interrupts::without_interrupts;
 use crate::include::symbols;
-use crate::memory::physicalmemory::{PhysicalMemoryError, BITMAP};
+use crate::memory::addressspace;
+use crate::memory::ioremap;
+use crate::memory::pae::{self, PAE_DIRECTORY};
+use crate::memory::pageflags::PageFlags;
+use crate::memory::physicalmemory::{
+	alloc_frame_or_evict, PhysicalMemoryError, BITMAP, HIGH_MEMORY_START,
+};
 use core::arch::asm;
+use core::ops::Range;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 
 pub const PDA: usize = 0x1000;
+// Page used by ```with_frame```, its page table is created at init.
+const SCRATCH_PAGE: usize = 0xFF7F_F000;
+// Page tables of the active space seen through the recursive slot.
+pub const RECURSIVE_BASE: usize = 0xFFC0_0000;
+
+static PAGING_ENABLED: AtomicBool = AtomicBool::new(false);
+static PSE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum VirtualMemoryError {
+	NotMapped,
+	AlreadyMapped,
+}
 
 pub struct PageTableEntry(usize);
 
 impl PageTableEntry {
-	fn new(address: usize, flags: usize) -> PageTableEntry {
+	fn new(address: usize, flags: PageFlags) -> PageTableEntry {
 		assert_eq!(0, address & 0xFFF);
-		PageTableEntry(address | flags)
+		// 32 bits entries, no NO_EXECUTE
+		PageTableEntry(address | (flags.bits() & 0xFFF) as usize)
 	}
 
 	pub fn is_present(&self) -> bool {
-		self.0 & 0x1 != 0
+		self.flags().contains(PageFlags::PRESENT)
+	}
+
+	pub fn flags(&self) -> PageFlags {
+		PageFlags::from_entry(self.0 as u64)
 	}
 
 	pub fn page_frame_address(&self) -> usize {
@@ -37,11 +66,11 @@ impl PageTable {
 
 	fn clear(&mut self) {
 		for i in 0..self.ref_table().len() {
-			self.mut_table()[i] = PageTableEntry::new(0, 0)
+			self.mut_table()[i] = PageTableEntry::new(0, PageFlags::empty())
 		}
 	}
 
-	fn set_entry(&mut self, index: usize, address: usize, flags: usize) {
+	fn set_entry(&mut self, index: usize, address: usize, flags: PageFlags) {
 		// 	if index % 10 == 0 {
 		// 	crate::println!(
 		// 		"set_entry table: index : {}, address : 0x{:x}, flags : {}",
@@ -59,22 +88,35 @@ pub struct PageDirectoryEntry(usize);
 
 #[allow(unused)]
 impl PageDirectoryEntry {
-	fn new(address: usize, flags: usize) -> PageDirectoryEntry {
+	fn new(address: usize, flags: PageFlags) -> PageDirectoryEntry {
 		assert_eq!(0, address & 0xFFF);
-		PageDirectoryEntry(address | flags)
+		PageDirectoryEntry(address | (flags.bits() & 0xFFF) as usize)
 	}
 
 	pub fn page_table_address(&self) -> usize {
 		self.0 & 0xFFFFF000
 	}
 
+	pub fn flags(&self) -> PageFlags {
+		PageFlags::from_entry(self.0 as u64)
+	}
+
 	pub fn is_present(&self) -> bool {
-		self.0 & 0x1 != 0
+		self.flags().contains(PageFlags::PRESENT)
+	}
+
+	/// Present and mapping a 4MB page instead of a page table.
+	pub fn is_large(&self) -> bool {
+		self.flags().contains(PageFlags::PRESENT | PageFlags::LARGE)
 	}
 }
 
+/// ## PageDirectory
+/// The directory, whether it is reached through the recursive slot, and the number
+/// of present pages in each page table of the active space. \
+/// A table is freed when its last page is unmapped.
 #[repr(C, align(4096))]
-pub struct PageDirectory(pub NonNull<[PageDirectoryEntry; 1024]>, bool);
+pub struct PageDirectory(pub NonNull<[PageDirectoryEntry; 1024]>, bool, [u16; 1024]);
 
 impl PageDirectory {
 	pub fn ref_dir(&self) -> &[PageDirectoryEntry; 1024] {
@@ -87,19 +129,20 @@ impl PageDirectory {
 
 	pub fn clear(&mut self) {
 		for i in 0..self.ref_dir().len() {
-			self.mut_dir()[i] = PageDirectoryEntry::new(0, 0);
+			self.mut_dir()[i] = PageDirectoryEntry::new(0, PageFlags::empty());
 		}
+		self.2 = [0; 1024];
 	}
 
 	fn table_address_add(&self, offset: usize) -> usize {
 		if self.1 {
-			0xFFC00000usize + (offset << 12) // if recursive mapping on
+			RECURSIVE_BASE + (offset << 12) // if recursive mapping on
 		} else {
 			self.ref_dir()[offset].page_table_address()
 		}
 	}
 
-	pub fn set_entry(&mut self, index: usize, address: usize, flags: usize) {
+	pub fn set_entry(&mut self, index: usize, address: usize, flags: PageFlags) {
 		// crate::println!(
 		// 	"set_entry directory: index : {}, address : 0x{:x}, flags : {}",
 		// 	index,
@@ -109,12 +152,18 @@ impl PageDirectory {
 		self.mut_dir()[index] = PageDirectoryEntry::new(address, flags)
 	}
 
+	#[track_caller]
 	pub fn map_page(
 		&mut self,
 		virtual_address: usize,
 		physical_address: usize,
-		flags: usize,
+		flags: PageFlags,
 	) -> Result<(), PhysicalMemoryError> {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY
+				.lock()
+				.map_page(virtual_address, physical_address as u64, flags);
+		}
 		assert!(virtual_address & 0xFFF == 0, "Address is not 4KB aligned");
 		assert!(physical_address & 0xFFF == 0, "Address is not 4KB aligned");
 		let pdi = virtual_address >> 22;
@@ -124,18 +173,26 @@ impl PageDirectory {
 
 		// crate::println!("map_page virtual_address: 0x{:x}", virtual_address);
 
+		assert!(
+			!self.ref_dir()[pdi].is_large(),
+			"inside a large page. address: 0x{:x}",
+			virtual_address
+		);
+
 		let mut page_table: PageTable;
 		let page_table_add: usize;
 		if !self.ref_dir()[pdi].is_present() {
-			page_table_add = BITMAP.lock().alloc_frame()?;
-			self.set_entry(pdi, page_table_add, 0x3);
+			page_table_add = BITMAP.lock().alloc_page_table()?;
+			self.set_entry(pdi, page_table_add, PageFlags::KERNEL);
 			page_table =
 				unsafe { PageTable(NonNull::new_unchecked(self.table_address_add(pdi) as *mut _)) };
 			page_table.clear();
+			addressspace::share_kernel_entry(virtual_address, self.ref_dir()[pdi].0 as u64);
 		} else {
 			page_table =
 				unsafe { PageTable(NonNull::new_unchecked(self.table_address_add(pdi) as *mut _)) }
 		}
+		self.allow_user(pdi, flags);
 		assert!(
 			!page_table.ref_table()[pti].is_present(),
 			"page entry already present. address: 0x{:x}, pti: {}",
@@ -143,10 +200,32 @@ impl PageDirectory {
 			pti
 		);
 		page_table.set_entry(pti, physical_address, flags);
+		self.2[pdi] += 1;
+		flush_page(virtual_address);
 		Ok(())
 	}
 
+	/// ## Unmap_page
+	/// Remove the mapping and drop its reference on the frame,
+	/// the frame is freed when nothing else maps it.
+	#[track_caller]
 	pub fn unmap_page(&mut self, virtual_address: usize) -> Result<(), PhysicalMemoryError> {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().unmap_page(virtual_address);
+		}
+		let frame = self.forget_page(virtual_address);
+		BITMAP.lock().put_frame(frame as usize)?;
+		Ok(())
+	}
+
+	/// ## Forget_page
+	/// Remove the mapping but leave the frame alone, ex) device memory. \
+	/// Return the physical address it mapped.
+	#[track_caller]
+	pub fn forget_page(&mut self, virtual_address: usize) -> u64 {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().forget_page(virtual_address);
+		}
 		let pdi = (virtual_address >> 22) & 0x3FF;
 		let pti = (virtual_address >> 12) & 0x3FF;
 
@@ -156,6 +235,11 @@ impl PageDirectory {
 			"Directory entry not preset. virtual address: 0x{:x}",
 			virtual_address
 		);
+		assert!(
+			!self.ref_dir()[pdi].is_large(),
+			"inside a large page, use unmap_large_page. virtual address: 0x{:x}",
+			virtual_address
+		);
 
 		let mut page_table =
 			unsafe { PageTable(NonNull::new_unchecked(self.table_address_add(pdi) as *mut _)) };
@@ -165,14 +249,96 @@ impl PageDirectory {
 			virtual_address,
 			pti
 		);
-		BITMAP
-			.lock()
-			.free_frame(page_table.ref_table()[pti].page_frame_address())?;
-		page_table.set_entry(pti, 0x0, 0x0);
-		Ok(())
+		let frame = page_table.ref_table()[pti].page_frame_address();
+		page_table.set_entry(pti, 0x0, PageFlags::empty());
+		flush_page(virtual_address);
+		self.2[pdi] -= 1;
+		if self.2[pdi] == 0 {
+			self.release_table(pdi);
+		}
+		frame as u64
+	}
+
+	/// ## Replace_entry
+	/// Write the raw page table entry of `virtual_address`, return the old one,
+	/// ex) a swap entry in place of a present page. \
+	/// The page table must be there. A table is kept while one of its entries
+	/// is not zero, present or not.
+	pub fn replace_entry(&mut self, virtual_address: usize, entry: u64) -> u64 {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().replace_entry(virtual_address, entry);
+		}
+		let pdi = (virtual_address >> 22) & 0x3FF;
+		let pti = (virtual_address >> 12) & 0x3FF;
+		let directory_entry = &self.ref_dir()[pdi];
+		assert!(
+			directory_entry.is_present() && !directory_entry.is_large(),
+			"no page table. virtual address: 0x{:x}",
+			virtual_address
+		);
+		let mut page_table =
+			unsafe { PageTable(NonNull::new_unchecked(self.table_address_add(pdi) as *mut _)) };
+		let old = page_table.ref_table()[pti].0 as u64;
+		page_table.mut_table()[pti] = PageTableEntry(entry as usize);
+		flush_page(virtual_address);
+		match (old != 0, entry != 0) {
+			(false, true) => self.2[pdi] += 1,
+			(true, false) => {
+				self.2[pdi] -= 1;
+				if self.2[pdi] == 0 {
+					self.release_table(pdi);
+				}
+			}
+			_ => {}
+		}
+		old
+	}
+
+	/// ## Release_table
+	/// Free the empty page table of `pdi` and clear its directory entry,
+	/// in every space for a kernel table.
+	fn release_table(&mut self, pdi: usize) {
+		let (table, view) = (
+			self.ref_dir()[pdi].page_table_address(),
+			self.table_address_add(pdi),
+		);
+		self.set_entry(pdi, 0x0, PageFlags::empty());
+		if self.1 {
+			flush_page(view);
+		}
+		BITMAP.lock().free_page_table(table).unwrap();
+		addressspace::share_kernel_entry(pdi << 22, 0);
+	}
+
+	/// ## Recount
+	/// Count the used entries of the tables of `range` again, after CR3 changed. \
+	/// Only the private tables differ between spaces, the kernel counts stay right.
+	pub fn recount(&mut self, range: Range<usize>) {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().recount(range);
+		}
+		for pdi in range.start >> 22..range.end >> 22 {
+			let entry = &self.ref_dir()[pdi];
+			self.2[pdi] = if entry.is_present() && !entry.is_large() {
+				let page_table = unsafe {
+					PageTable(NonNull::new_unchecked(self.table_address_add(pdi) as *mut _))
+				};
+				page_table
+					.ref_table()
+					.iter()
+					// swapped pages keep their table too
+					.filter(|e| e.0 != 0)
+					.count() as u16
+			} else {
+				0
+			};
+		}
 	}
 
 	pub fn translate(&mut self, virtual_address: usize) -> usize {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().translate(virtual_address) as usize;
+		}
 		let pdi = (virtual_address >> 22) & 0x3FF;
 		let pti = (virtual_address >> 12) & 0x3FF;
 
@@ -181,6 +347,9 @@ impl PageDirectory {
 			"Directory entry not preset. virtual address: 0x{:x}",
 			virtual_address
 		);
+		if self.ref_dir()[pdi].is_large() {
+			return self.ref_dir()[pdi].page_table_address() + (virtual_address & 0x3F_FFFF);
+		}
 
 		let page_table =
 			unsafe { PageTable(NonNull::new_unchecked(self.table_address_add(pdi) as *mut _)) };
@@ -194,13 +363,293 @@ impl PageDirectory {
 		physical_address
 	}
 
+	/// A user page needs the user bit in its directory entry too,
+	/// in every space for a kernel table.
+	fn allow_user(&mut self, pdi: usize, flags: PageFlags) {
+		let entry = &self.ref_dir()[pdi];
+		if flags.contains(PageFlags::USER) && !entry.flags().contains(PageFlags::USER) {
+			let (address, flags) = (entry.page_table_address(), entry.flags());
+			self.set_entry(pdi, address, flags | PageFlags::USER);
+			addressspace::share_kernel_entry(pdi << 22, self.ref_dir()[pdi].0 as u64);
+		}
+	}
+
+	/// Physical frame and flags of the page at `virtual_address`, None if not mapped.
+	pub fn query(&self, virtual_address: usize) -> Option<(u64, PageFlags)> {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().query(virtual_address);
+		}
+		let pdi = (virtual_address >> 22) & 0x3FF;
+		let pti = (virtual_address >> 12) & 0x3FF;
+		let directory_entry = &self.ref_dir()[pdi];
+		if !directory_entry.is_present() {
+			return None;
+		}
+		if directory_entry.is_large() {
+			let frame = directory_entry.page_table_address() + (pti << 12);
+			return Some((frame as u64, directory_entry.flags()));
+		}
+		let page_table =
+			unsafe { PageTable(NonNull::new_unchecked(self.table_address_add(pdi) as *mut _)) };
+		let entry = &page_table.ref_table()[pti];
+		entry
+			.is_present()
+			.then(|| (entry.page_frame_address() as u64, entry.flags()))
+	}
+
+	/// ## Walk
+	/// Raw directory and page table entries of `virtual_address`. \
+	/// No page table entry for a large page or a missing table.
+	pub fn walk(&self, virtual_address: usize) -> (u64, Option<u64>) {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().walk(virtual_address);
+		}
+		let pdi = (virtual_address >> 22) & 0x3FF;
+		let pti = (virtual_address >> 12) & 0x3FF;
+		let directory_entry = &self.ref_dir()[pdi];
+		if !directory_entry.is_present() || directory_entry.is_large() {
+			return (directory_entry.0 as u64, None);
+		}
+		let page_table =
+			unsafe { PageTable(NonNull::new_unchecked(self.table_address_add(pdi) as *mut _)) };
+		(
+			directory_entry.0 as u64,
+			Some(page_table.ref_table()[pti].0 as u64),
+		)
+	}
+
+	/// ## Protect
+	/// Replace the flags of every page in `range` as ```PageFlags::protected``` does
+	/// and flush the pages from the TLB. \
+	/// A large page takes the flags as a whole. \
+	/// Nothing changes if a page of the range is not mapped.
+	pub fn protect(
+		&mut self,
+		range: Range<usize>,
+		flags: PageFlags,
+	) -> Result<(), VirtualMemoryError> {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().protect(range, flags);
+		}
+		let pages = (range.start & !0xFFF..range.end).step_by(0x1000);
+		if pages.clone().any(|address| self.query(address).is_none()) {
+			return Err(VirtualMemoryError::NotMapped);
+		}
+		for address in pages {
+			let pdi = (address >> 22) & 0x3FF;
+			let pti = (address >> 12) & 0x3FF;
+			if self.ref_dir()[pdi].is_large() {
+				let entry = &self.ref_dir()[pdi];
+				let (frame, old) = (entry.page_table_address(), entry.flags());
+				self.set_entry(pdi, frame, old.protected(flags));
+				addressspace::share_kernel_entry(address, self.ref_dir()[pdi].0 as u64);
+				flush_page(address);
+				continue;
+			}
+			self.allow_user(pdi, flags);
+			let mut page_table =
+				unsafe { PageTable(NonNull::new_unchecked(self.table_address_add(pdi) as *mut _)) };
+			let entry = &page_table.ref_table()[pti];
+			let (frame, old) = (entry.page_frame_address(), entry.flags());
+			page_table.set_entry(pti, frame, old.protected(flags));
+			flush_page(address);
+		}
+		Ok(())
+	}
+
+	/// ## For_each_mapping
+	/// Call `f` with the virtual and physical address of every present page
+	/// in `[start, end)`.
+	pub fn for_each_mapping(&self, start: usize, end: usize, mut f: impl FnMut(usize, u64)) {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().for_each_mapping(start, end, f);
+		}
+		if start >= end {
+			return;
+		}
+		for pdi in (start >> 22)..=((end - 1) >> 22).min(1022) {
+			let directory_entry = &self.ref_dir()[pdi];
+			if !directory_entry.is_present() {
+				continue;
+			}
+			if directory_entry.is_large() {
+				for pti in 0..1024 {
+					let virtual_address = pdi << 22 | pti << 12;
+					if start <= virtual_address && virtual_address < end {
+						let frame = directory_entry.page_table_address() + (pti << 12);
+						f(virtual_address, frame as u64);
+					}
+				}
+				continue;
+			}
+			let page_table =
+				unsafe { PageTable(NonNull::new_unchecked(self.table_address_add(pdi) as *mut _)) };
+			for (pti, entry) in page_table.ref_table().iter().enumerate() {
+				let virtual_address = pdi << 22 | pti << 12;
+				if entry.is_present() && start <= virtual_address && virtual_address < end {
+					f(virtual_address, entry.page_frame_address() as u64);
+				}
+			}
+		}
+	}
+
+	/// Call `f` with the physical address of the directory and of every page table.
+	pub fn for_each_page_table(&self, mut f: impl FnMut(u64)) {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().for_each_page_table(f);
+		}
+		// the recursive entry is the directory itself
+		f(self.ref_dir()[1023].page_table_address() as u64);
+		for entry in self.ref_dir()[..1023]
+			.iter()
+			.filter(|e| e.is_present() && !e.is_large())
+		{
+			f(entry.page_table_address() as u64);
+		}
+	}
+
+	/// ## Map_large_page
+	/// Map ```large_page_size``` bytes with a single directory entry. \
+	/// An empty page table in the slot is freed, Err if one of its pages is mapped.
+	#[track_caller]
+	pub fn map_large_page(
+		&mut self,
+		virtual_address: usize,
+		physical_address: usize,
+		flags: PageFlags,
+	) -> Result<(), VirtualMemoryError> {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().map_large_page(
+				virtual_address,
+				physical_address as u64,
+				flags,
+			);
+		}
+		assert!(PSE.load(Ordering::Relaxed), "PSE is not enabled");
+		assert!(
+			virtual_address & 0x3F_FFFF == 0 && physical_address & 0x3F_FFFF == 0,
+			"Address is not 4MB aligned"
+		);
+		let pdi = virtual_address >> 22;
+		assert!(pdi != 1023, "over 0xFFC00000 is reserved");
+
+		let entry = &self.ref_dir()[pdi];
+		if entry.is_present() {
+			assert!(
+				!entry.is_large(),
+				"large page already present. address: 0x{:x}",
+				virtual_address
+			);
+			if self.2[pdi] != 0 {
+				return Err(VirtualMemoryError::AlreadyMapped);
+			}
+			let table_address = self.table_address_add(pdi);
+			BITMAP
+				.lock()
+				.free_page_table(entry.page_table_address())
+				.unwrap();
+			if self.1 {
+				// the recursive view of the freed table
+				flush_page(table_address);
+			}
+		}
+		self.set_entry(pdi, physical_address, flags | PageFlags::LARGE);
+		addressspace::share_kernel_entry(virtual_address, self.ref_dir()[pdi].0 as u64);
+		flush_page(virtual_address);
+		Ok(())
+	}
+
+	/// ## Unmap_large_page
+	/// Remove the large page at `virtual_address` and drop the reference of each
+	/// of its frames.
+	#[track_caller]
+	pub fn unmap_large_page(&mut self, virtual_address: usize) -> Result<(), PhysicalMemoryError> {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().unmap_large_page(virtual_address);
+		}
+		let pdi = (virtual_address >> 22) & 0x3FF;
+		assert!(
+			self.ref_dir()[pdi].is_large(),
+			"no large page. virtual address: 0x{:x}",
+			virtual_address
+		);
+		let base = self.ref_dir()[pdi].page_table_address();
+		{
+			let mut bitmap = BITMAP.lock();
+			for frame in (base..base + 0x40_0000).step_by(0x1000) {
+				bitmap.put_frame(frame)?;
+			}
+		}
+		self.set_entry(pdi, 0x0, PageFlags::empty());
+		addressspace::share_kernel_entry(virtual_address, 0);
+		flush_page(virtual_address);
+		Ok(())
+	}
+
+	/// ## Map_range
+	/// Map `size` bytes from `virtual_address` to `physical_address`, with large pages
+	/// where both are aligned and the slot is free, 4KB pages elsewhere.
+	pub fn map_range(
+		&mut self,
+		virtual_address: usize,
+		physical_address: usize,
+		size: usize,
+		flags: PageFlags,
+	) -> Result<(), PhysicalMemoryError> {
+		let large = large_page_size();
+		let mut offset = 0;
+		while offset < size {
+			let (virtual_address, physical_address) =
+				(virtual_address + offset, physical_address + offset);
+			if large_pages_enabled()
+				&& (virtual_address | physical_address) & (large - 1) == 0
+				&& size - offset >= large
+				&& self
+					.map_large_page(virtual_address, physical_address, flags)
+					.is_ok()
+			{
+				offset += large;
+				continue;
+			}
+			self.map_page(virtual_address, physical_address, flags)?;
+			offset += 0x1000;
+		}
+		Ok(())
+	}
+
+	/// Unmap `size` bytes mapped with ```map_range```.
+	pub fn unmap_range(
+		&mut self,
+		virtual_address: usize,
+		size: usize,
+	) -> Result<(), PhysicalMemoryError> {
+		let mut offset = 0;
+		while offset < size {
+			let address = virtual_address + offset;
+			let large = self
+				.query(address)
+				.is_some_and(|(_, flags)| flags.contains(PageFlags::LARGE));
+			if large {
+				self.unmap_large_page(address)?;
+				offset += large_page_size();
+			} else {
+				self.unmap_page(address)?;
+				offset += 0x1000;
+			}
+		}
+		Ok(())
+	}
+
 	pub fn init_directory(&mut self, start_addr: usize, end_addr: usize) {
+		if pae::is_enabled() {
+			return PAE_DIRECTORY.lock().init_directory(start_addr, end_addr);
+		}
 		let mut page_table: PageTable;
 		for i in (start_addr..=end_addr).step_by(4096) {
 			let pdi = i >> 22;
 			if !self.ref_dir()[pdi].is_present() {
-				let page_table_add = BITMAP.lock().alloc_frame().unwrap();
-				self.set_entry(pdi, page_table_add, 0x3);
+				let page_table_add = BITMAP.lock().alloc_page_table().unwrap();
+				self.set_entry(pdi, page_table_add, PageFlags::KERNEL);
 				page_table = unsafe {
 					PageTable(NonNull::new_unchecked(self.table_address_add(pdi) as *mut _))
 				};
@@ -215,30 +664,50 @@ unsafe impl Send for PageDirectory {}
 pub static PAGE_DIRECTORY: Mutex<PageDirectory> = Mutex::new(PageDirectory(
 	unsafe { NonNull::new_unchecked(PDA as *mut _) },
 	false,
+	[0; 1024],
 ));
 
 pub fn init(multiboot_info: usize, paging_status: bool) {
 	if !paging_status {
 		return;
 	}
-	let mut kernel_start_page = symbols::get_kernel_start() as usize & !0xFFF;
+	let kernel_start_page = symbols::get_kernel_start() as usize & !0xFFF;
 	let kernel_end_page = symbols::get_kernel_end() as usize & !0xFFF;
 	let multiboot_frame_add = multiboot_info & !0xFFF;
 
+	// `pae=on` switches to 3 levels tables with 64 bits entries, the calls
+	// below go through the same PAGE_DIRECTORY methods.
+	if cmdline::option("pae") == Some("on") && pae::is_supported() {
+		pae::setup();
+	} else if pse_supported() {
+		enable_pse();
+	}
 	PAGE_DIRECTORY.lock().clear();
 
-	PAGE_DIRECTORY.lock().map_page(0x0, 0x0, 0x3).unwrap();
 	PAGE_DIRECTORY
 		.lock()
-		.map_page(0xb8000, 0xb8000, 0x3)
+		.map_page(0x0, 0x0, PageFlags::KERNEL)
+		.unwrap();
+	PAGE_DIRECTORY
+		.lock()
+		.map_page(0xb8000, 0xb8000, PageFlags::KERNEL)
 		.unwrap();
 	// crate::println!("[VIRTUAL]  kernel alloc: 0x{:08x}, 0x{:08x}", kernel_start_page, kernel_end_page);
-	while kernel_start_page <= kernel_end_page {
+	PAGE_DIRECTORY
+		.lock()
+		.map_range(
+			kernel_start_page,
+			kernel_start_page,
+			kernel_end_page + 0x1000 - kernel_start_page,
+			PageFlags::KERNEL,
+		)
+		.unwrap();
+	let metadata_regions = BITMAP.lock().metadata_regions();
+	for (start, end) in metadata_regions {
 		PAGE_DIRECTORY
 			.lock()
-			.map_page(kernel_start_page, kernel_start_page, 0x3)
+			.map_range(start, start, end - start, PageFlags::KERNEL)
 			.unwrap();
-		kernel_start_page += 0x1000;
 	}
 	// crate::println!("kernel_start: {}", symbols::get_kernel_start as usize);
 	// crate::println!("kernel_end: {}", symbols::get_kernel_end as usize);
@@ -249,22 +718,167 @@ pub fn init(multiboot_info: usize, paging_status: bool) {
 	{
 		PAGE_DIRECTORY
 			.lock()
-			.map_page(multiboot_frame_add, multiboot_frame_add, 0x3)
+			.map_page(multiboot_frame_add, multiboot_frame_add, PageFlags::KERNEL)
 			.unwrap();
 	}
 	// PAGE_DIRECTORY
 	// 	.lock()
 	// 	.map_page(0x800B_5000, 0x800B_5000, 0x3)
 	// 	.unwrap();
-	PAGE_DIRECTORY.lock().set_entry(1023, PDA, 0x3);
-	unsafe { asm!("invlpg [0]") };
-	enable(PDA);
-	*PAGE_DIRECTORY.lock() = unsafe {
-		PageDirectory(
-			NonNull::new_unchecked((0x3FFusize << 22 | 0x3FFusize << 12) as *mut _),
-			true,
-		)
+	PAGE_DIRECTORY
+		.lock()
+		.init_directory(SCRATCH_PAGE, SCRATCH_PAGE);
+	if pae::is_enabled() {
+		pae::enable();
+	} else {
+		PAGE_DIRECTORY
+			.lock()
+			.set_entry(1023, PDA, PageFlags::KERNEL);
+		unsafe { asm!("invlpg [0]") };
+		enable(PDA);
+		let mut directory = PAGE_DIRECTORY.lock();
+		directory.0 =
+			unsafe { NonNull::new_unchecked((0x3FFusize << 22 | 0x3FFusize << 12) as *mut _) };
+		directory.1 = true;
+	}
+	PAGING_ENABLED.store(true, Ordering::Relaxed);
+	addressspace::init();
+	ioremap::init();
+}
+
+pub fn is_paging_enabled() -> bool {
+	PAGING_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Size of a large page, 4MB with PSE or 2MB with PAE.
+pub fn large_page_size() -> usize {
+	if pae::is_enabled() {
+		0x20_0000
+	} else {
+		0x40_0000
+	}
+}
+
+/// True when ```map_large_page``` can be used, PAE always has large pages.
+pub fn large_pages_enabled() -> bool {
+	pae::is_enabled() || PSE.load(Ordering::Relaxed)
+}
+
+fn pse_supported() -> bool {
+	cpuid(1).edx & (1 << 3) != 0
+}
+
+/// Turn on 4MB pages, the page size bit of a directory entry is ignored before.
+fn enable_pse() {
+	unsafe {
+		asm!(
+			"mov {tmp}, cr4",
+			"or {tmp}, 0x10",
+			"mov cr4, {tmp}",
+			tmp = out(reg) _,
+		);
+	}
+	PSE.store(true, Ordering::Relaxed);
+}
+
+/// Drop the TLB entry of the page at `virtual_address`.
+pub fn flush_page(virtual_address: usize) {
+	unsafe { asm!("invlpg [{}]", in(reg) virtual_address, options(nostack, preserves_flags)) };
+}
+
+/// Drop every TLB entry by reloading CR3, cheaper than a page by page flush
+/// for a big range.
+pub fn flush_all() {
+	unsafe {
+		asm!(
+			"mov {tmp}, cr3",
+			"mov cr3, {tmp}",
+			tmp = out(reg) _,
+			options(nostack, preserves_flags)
+		);
+	}
+}
+
+/// ## Handle_cow_fault
+/// Resolve a write to a present page of the active space. \
+/// A ```PageFlags::COW``` page gets a copy of its frame, or keeps the frame when
+/// it holds the last reference, and becomes writable. \
+/// Return false when the page is not copy-on-write or there is no memory left.
+pub fn handle_cow_fault(virtual_address: usize) -> bool {
+	let page = virtual_address & !0xFFF;
+	let Some((frame, flags)) = PAGE_DIRECTORY.lock().query(page) else {
+		return false;
 	};
+	if !flags.contains(PageFlags::COW) {
+		return false;
+	}
+	// shared frames always have a reference count, see clone_cow
+	let frame = frame as usize;
+	// the copy is taken without the directory locked, it may swap out a page
+	let new_frame = if BITMAP.lock().refcount(frame) == 1 {
+		frame
+	} else {
+		match alloc_frame_or_evict() {
+			Ok(new_frame) => new_frame,
+			Err(_) => return false,
+		}
+	};
+	let mut directory = PAGE_DIRECTORY.lock();
+	if directory.query(page) != Some((frame as u64, flags)) {
+		// changed meanwhile, the access is retried
+		drop(directory);
+		if new_frame != frame {
+			BITMAP.lock().free_frame(new_frame).unwrap();
+		}
+		return true;
+	}
+	if new_frame != frame {
+		with_frame(new_frame as u64, |destination| unsafe {
+			destination.copy_from_nonoverlapping(page as *const u8, 0x1000)
+		});
+	}
+	let flags =
+		(flags & !(PageFlags::STATUS | PageFlags::COW)) | PageFlags::PRESENT | PageFlags::WRITABLE;
+	// in place, the table stays
+	directory.replace_entry(page, new_frame as u64 | flags.bits());
+	if new_frame != frame {
+		// a reserved frame has no count to drop
+		let _ = BITMAP.lock().put_frame(frame);
+	}
+	true
+}
+
+/// Write the page table entry of the scratch page through the recursive mapping.
+unsafe fn set_scratch_entry(entry: u64) {
+	if pae::is_enabled() {
+		let address = 0xFF80_0000 + (SCRATCH_PAGE >> 12) * 8;
+		(address as *mut u64).write_volatile(entry);
+	} else {
+		let address = 0xFFC0_0000 + (SCRATCH_PAGE >> 12) * 4;
+		(address as *mut usize).write_volatile(entry as usize);
+	}
+	flush_page(SCRATCH_PAGE);
+}
+
+/// ## With_frame
+/// Run `f` with a pointer to the physical frame at `physical_address`, mapped on a
+/// scratch page while paging is on. \
+/// Doesn't take the ```PAGE_DIRECTORY``` lock, so it works while it's held,
+/// ex) from ```unmap_page```. `f` must not call ```with_frame``` again.
+pub fn with_frame<R>(physical_address: u64, f: impl FnOnce(*mut u8) -> R) -> R {
+	if !PAGING_ENABLED.load(Ordering::Relaxed) {
+		return f(physical_address as usize as *mut u8);
+	}
+	assert!(
+		physical_address < HIGH_MEMORY_START || pae::is_enabled(),
+		"frame over 4GB without PAE"
+	);
+	without_interrupts(|| unsafe {
+		set_scratch_entry(physical_address | PageFlags::KERNEL.bits());
+		let ret = f(SCRATCH_PAGE as *mut u8);
+		set_scratch_entry(0);
+		ret
+	})
 }
 
 fn enable(page_dir_address: usize) {
@@ -272,7 +886,8 @@ fn enable(page_dir_address: usize) {
 		asm!(
 			"mov cr3, {pda}",
 			"mov {tmp}, cr0",
-			"or {tmp}, 0x80000000",
+			// WP, so kernel writes to read only pages fault too
+			"or {tmp}, 0x80010000",
 			"mov cr0, {tmp}",
 			pda = in(reg) page_dir_address,
 			tmp = out(reg) _,
@@ -0,0 +1,61 @@
+use crate::memory::pageflags::PageFlags;
+use crate::memory::physicalmemory::{self, BITMAP};
+use crate::memory::virtualmemory::PAGE_DIRECTORY;
+use crate::memory::vrange::VirtualRanges;
+use core::ptr::null_mut;
+use spin::Mutex;
+
+// Kernel range of vmalloc areas, shared by every address space.
+pub const VMALLOC_START: usize = 0xC000_0000;
+pub const VMALLOC_END: usize = 0xE000_0000;
+
+// A guard page after each area.
+pub static VMALLOC: Mutex<VirtualRanges> =
+	Mutex::new(VirtualRanges::new(VMALLOC_START, VMALLOC_END, 1));
+
+/// ## Vmalloc
+/// Virtually contiguous kernel memory of `size` bytes, the frames are taken
+/// one by one so they need not be contiguous. \
+/// Return null when the range or the frames run out.
+/// ## Warning
+/// Free it with ```vfree``` and the same size.
+#[allow(unused)]
+pub fn vmalloc(size: usize) -> *mut u8 {
+	let size = size.div_ceil(0x1000) * 0x1000;
+	let Ok(start) = VMALLOC.lock().alloc(size, 0x1000) else {
+		return null_mut();
+	};
+	for address in (start..start + size).step_by(0x1000) {
+		let Ok(frame) = physicalmemory::alloc_frame_or_evict() else {
+			undo(start, address, size);
+			return null_mut();
+		};
+		if PAGE_DIRECTORY
+			.lock()
+			.map_page(address, frame, PageFlags::KERNEL)
+			.is_err()
+		{
+			BITMAP.lock().free_frame(frame).unwrap();
+			undo(start, address, size);
+			return null_mut();
+		}
+	}
+	start as *mut u8
+}
+
+/// Unmap the pages mapped up to `mapped_end` and give the whole range back.
+fn undo(start: usize, mapped_end: usize, size: usize) {
+	PAGE_DIRECTORY
+		.lock()
+		.unmap_range(start, mapped_end - start)
+		.unwrap();
+	VMALLOC.lock().free(start, size).unwrap();
+}
+
+/// Unmap and free an area of ```vmalloc```, `size` is the one it was asked with.
+#[allow(unused)]
+pub fn vfree(address: *mut u8, size: usize) {
+	let (start, size) = (address as usize, size.div_ceil(0x1000) * 0x1000);
+	PAGE_DIRECTORY.lock().unmap_range(start, size).unwrap();
+	VMALLOC.lock().free(start, size).unwrap();
+}
@@ -0,0 +1,146 @@
+// Free ranges kept apart at most, a free that would need more fails.
+const MAX_FREE_RANGES: usize = 256;
+
+#[derive(Debug)]
+pub enum VirtualRangeError {
+	OutOfSpace,
+	TooFragmented,
+	NotAllocated,
+}
+
+/// Free virtual range `[start, end)`.
+#[derive(Debug, Clone, Copy)]
+struct FreeRange {
+	start: usize,
+	end: usize,
+}
+
+const EMPTY_RANGE: FreeRange = FreeRange { start: 0, end: 0 };
+
+/// ## VirtualRanges
+/// First fit allocator of virtual ranges, the free ranges are sorted and merged
+/// when they touch. \
+/// Each allocation is followed by `guard` unmapped pages so an overflow faults
+/// instead of running into the next one. \
+/// A fixed array, the heaps use it and can't allocate while doing so.
+pub struct VirtualRanges {
+	free: [FreeRange; MAX_FREE_RANGES],
+	count: usize,
+	guard: usize,
+}
+
+impl VirtualRanges {
+	pub const fn new(start: usize, end: usize, guard_pages: usize) -> VirtualRanges {
+		let mut free = [EMPTY_RANGE; MAX_FREE_RANGES];
+		free[0] = FreeRange { start, end };
+		VirtualRanges {
+			free,
+			count: if start < end { 1 } else { 0 },
+			guard: guard_pages * 0x1000,
+		}
+	}
+
+	/// Bytes taken by an allocation of `size`, guard included.
+	fn span(&self, size: usize) -> usize {
+		size.div_ceil(0x1000) * 0x1000 + self.guard
+	}
+
+	fn insert(&mut self, index: usize, range: FreeRange) -> Result<(), VirtualRangeError> {
+		if self.count == MAX_FREE_RANGES {
+			return Err(VirtualRangeError::TooFragmented);
+		}
+		self.free.copy_within(index..self.count, index + 1);
+		self.free[index] = range;
+		self.count += 1;
+		Ok(())
+	}
+
+	fn remove(&mut self, index: usize) {
+		self.free.copy_within(index + 1..self.count, index);
+		self.count -= 1;
+	}
+
+	/// ## Alloc
+	/// First free range where `size` bytes fit at a multiple of `align`,
+	/// a power of 2 of at least 4KB. \
+	/// Return the start of the range.
+	pub fn alloc(&mut self, size: usize, align: usize) -> Result<usize, VirtualRangeError> {
+		assert!(
+			align.is_power_of_two() && align >= 0x1000,
+			"bad alignment 0x{:x}",
+			align
+		);
+		let span = self.span(size);
+		let found = self.free[..self.count].iter().position(|r| {
+			let start = (r.start + align - 1) & !(align - 1);
+			start >= r.start && start.checked_add(span).is_some_and(|end| end <= r.end)
+		});
+		let index = found.ok_or(VirtualRangeError::OutOfSpace)?;
+		let range = self.free[index];
+		let start = (range.start + align - 1) & !(align - 1);
+		let end = start + span;
+
+		match (range.start < start, end < range.end) {
+			(false, false) => self.remove(index),
+			(false, true) => self.free[index].start = end,
+			(true, false) => self.free[index].end = start,
+			(true, true) => {
+				// split in two around the allocation
+				self.insert(
+					index + 1,
+					FreeRange {
+						start: end,
+						end: range.end,
+					},
+				)?;
+				self.free[index].end = start;
+			}
+		}
+		Ok(start)
+	}
+
+	/// ## Free
+	/// Give back the range at `start` allocated with `size`. \
+	/// Err if part of it is already free.
+	pub fn free(&mut self, start: usize, size: usize) -> Result<(), VirtualRangeError> {
+		let end = start + self.span(size);
+		let free = &self.free[..self.count];
+		if free.iter().any(|r| r.start < end && start < r.end) {
+			return Err(VirtualRangeError::NotAllocated);
+		}
+		let index = free
+			.iter()
+			.position(|r| r.start > start)
+			.unwrap_or(self.count);
+		let merge_previous = index > 0 && self.free[index - 1].end == start;
+		let merge_next = index < self.count && self.free[index].start == end;
+
+		match (merge_previous, merge_next) {
+			(true, true) => {
+				self.free[index - 1].end = self.free[index].end;
+				self.remove(index);
+			}
+			(true, false) => self.free[index - 1].end = end,
+			(false, true) => self.free[index].start = start,
+			(false, false) => self.insert(index, FreeRange { start, end })?,
+		}
+		Ok(())
+	}
+
+	/// Free bytes in every range.
+	pub fn free_size(&self) -> usize {
+		self.free[..self.count]
+			.iter()
+			.map(|r| r.end - r.start)
+			.sum()
+	}
+
+	/// Biggest free range, an allocation also needs room for the guard.
+	pub fn largest_free(&self) -> usize {
+		self.free[..self.count]
+			.iter()
+			.map(|r| r.end - r.start)
+			.max()
+			.unwrap_or(0)
+	}
+}
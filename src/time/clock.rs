@@ -0,0 +1,170 @@
+use crate::include::asm_utile::hlt;
+use crate::include::interrupts;
+use core::arch::asm;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+pub use core::time::Duration;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// i386 has no 64-bit atomics, the tick counter is split in two words.
+// Only the timer interrupt writes them, so a reader that sees the same
+// high word before and after reading the low word got a consistent value.
+static TICKS_LOW: AtomicUsize = AtomicUsize::new(0);
+static TICKS_HIGH: AtomicUsize = AtomicUsize::new(0);
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+static TICK_SOURCE: AtomicU8 = AtomicU8::new(TickSource::Pit as u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TickSource {
+	Pit,
+	Rtc,
+	Apic,
+}
+
+/// Called by `pit::configure_pit` with the programmed interrupt rate in Hz.
+pub fn set_frequency(frequency: u32) {
+	FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+pub fn frequency() -> u32 {
+	FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// ## Set_tick_source
+/// Select which interrupt advances the clock, and its rate in Hz. \
+/// Only at boot before `sti`: ticks already counted would be read with the new rate.
+pub fn set_tick_source(source: TickSource, frequency: u32) {
+	TICK_SOURCE.store(source as u8, Ordering::Relaxed);
+	set_frequency(frequency);
+}
+
+pub fn tick_source() -> TickSource {
+	match TICK_SOURCE.load(Ordering::Relaxed) {
+		1 => TickSource::Rtc,
+		2 => TickSource::Apic,
+		_ => TickSource::Pit,
+	}
+}
+
+/// ## Advance
+/// Advance the monotonic counter by `ticks` periods, more than one after a tickless idle. \
+/// Only the tick interrupt handlers and the idle loop (interrupts disabled) should call it.
+pub fn advance(ticks: usize) {
+	let previous = TICKS_LOW.fetch_add(ticks, Ordering::Release);
+	if previous.checked_add(ticks).is_none() {
+		TICKS_HIGH.fetch_add(1, Ordering::Release);
+	}
+}
+
+pub fn tick() {
+	advance(1);
+}
+
+/// Number of timer interrupts since boot. Safe to call from any context.
+pub fn ticks() -> u64 {
+	loop {
+		let high = TICKS_HIGH.load(Ordering::Acquire);
+		let low = TICKS_LOW.load(Ordering::Acquire);
+		if TICKS_HIGH.load(Ordering::Acquire) == high {
+			return (high as u64) << usize::BITS | low as u64;
+		}
+	}
+}
+
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+	let frequency = frequency() as u64;
+	if frequency == 0 {
+		return 0;
+	}
+	(ticks / frequency) * NANOS_PER_SEC + (ticks % frequency) * NANOS_PER_SEC / frequency
+}
+
+/// Convert a duration in timer ticks, rounded up. \
+/// A non zero duration always last at least one tick.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+	let frequency = frequency() as u64;
+	let nanos = duration.subsec_nanos() as u64 * frequency;
+	let ticks = duration.as_secs() * frequency + nanos.div_ceil(NANOS_PER_SEC);
+	if ticks == 0 && !duration.is_zero() {
+		1
+	} else {
+		ticks
+	}
+}
+
+/// Monotonic point in time, nanoseconds since the PIT was configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+#[allow(unused)]
+impl Instant {
+	pub fn now() -> Instant {
+		Instant(ticks_to_nanos(ticks()))
+	}
+
+	pub fn as_nanos(&self) -> u64 {
+		self.0
+	}
+
+	pub fn duration_since(&self, earlier: Instant) -> Duration {
+		Duration::from_nanos(self.0.saturating_sub(earlier.0))
+	}
+
+	pub fn elapsed(&self) -> Duration {
+		Instant::now().duration_since(*self)
+	}
+
+	pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+		let nanos = u64::try_from(duration.as_nanos()).ok()?;
+		self.0.checked_add(nanos).map(Instant)
+	}
+}
+
+impl Add<Duration> for Instant {
+	type Output = Instant;
+
+	fn add(self, duration: Duration) -> Instant {
+		self.checked_add(duration)
+			.expect("overflow when adding duration to instant")
+	}
+}
+
+impl Sub<Instant> for Instant {
+	type Output = Duration;
+
+	fn sub(self, earlier: Instant) -> Duration {
+		self.duration_since(earlier)
+	}
+}
+
+pub fn uptime() -> Duration {
+	Instant::now().duration_since(Instant(0))
+}
+
+/// ## Sleep
+/// Halt the cpu until at least `duration` has passed. \
+/// Interrupts are enabled while waiting, otherwise the tick counter would never move,
+/// and the previous state is restored before return. \
+/// Don't call it while holding a lock the timer interrupt may take.
+pub fn sleep(duration: Duration) {
+	let wait = duration_to_ticks(duration);
+	let start = ticks();
+	let sti = interrupts::is_enabled();
+
+	if !sti {
+		unsafe { asm!("sti") };
+	}
+	while ticks() - start < wait {
+		hlt();
+	}
+	if !sti {
+		unsafe { asm!("cli") };
+	}
+}
+
+pub fn sleep_ms(ms: u64) {
+	sleep(Duration::from_millis(ms));
+}
@@ -0,0 +1,139 @@
+use crate::include::apic;
+use crate::include::cmdline;
+use crate::time::clock::{self, TickSource};
+use crate::time::{pit, timer};
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Under this many ticks to the next timer, a plain periodic `hlt` is cheaper.
+const MIN_IDLE_TICKS: u64 = 2;
+
+static TICKLESS: AtomicBool = AtomicBool::new(true);
+// Length in ticks of the armed one-shot, 0 while the tick source is periodic.
+static ONESHOT_TICKS: AtomicU32 = AtomicU32::new(0);
+// Sub-tick time left over by early wake ups, in timer counts.
+static LEFTOVER_COUNTS: AtomicU32 = AtomicU32::new(0);
+
+/// Timer counts in one tick and the longest one-shot the tick source can do. \
+/// None when the source has no one-shot mode (RTC).
+fn oneshot_limits() -> Option<(u32, u32)> {
+	match clock::tick_source() {
+		TickSource::Pit => Some((pit::BASE_FREQUENCY / clock::frequency(), u16::MAX as u32)),
+		TickSource::Apic => Some((apic::timer_counts_per_tick(), u32::MAX)),
+		TickSource::Rtc => None,
+	}
+}
+
+fn start_oneshot(counts: u32) {
+	match clock::tick_source() {
+		TickSource::Pit => unsafe { pit::start_oneshot(counts as u16) },
+		TickSource::Apic => apic::start_timer_oneshot(counts),
+		TickSource::Rtc => {}
+	}
+}
+
+/// Remaining counts of the armed one-shot, None if it already expired.
+fn oneshot_remaining() -> Option<u32> {
+	match clock::tick_source() {
+		TickSource::Pit => unsafe {
+			if pit::oneshot_expired() {
+				None
+			} else {
+				Some(pit::read_count() as u32)
+			}
+		},
+		TickSource::Apic => match apic::timer_current() {
+			0 => None,
+			remaining => Some(remaining),
+		},
+		TickSource::Rtc => None,
+	}
+}
+
+fn restart_periodic() {
+	match clock::tick_source() {
+		TickSource::Pit => unsafe { pit::configure_pit(clock::frequency()) },
+		TickSource::Apic => apic::start_timer(clock::frequency()),
+		TickSource::Rtc => {}
+	}
+}
+
+pub fn is_tickless() -> bool {
+	TICKLESS.load(Ordering::Relaxed) && oneshot_limits().is_some()
+}
+
+/// Tickless idle is on by default, `nohz=off` keeps the periodic tick.
+pub fn init() {
+	TICKLESS.store(cmdline::option("nohz") != Some("off"), Ordering::Relaxed);
+}
+
+/// ## Timer_interrupt
+/// Called by the tick interrupt handler. \
+/// Return the ticks elapsed since the previous tick interrupt, and switch back
+/// to periodic mode if the interrupt ends a one-shot.
+pub fn timer_interrupt() -> usize {
+	match ONESHOT_TICKS.swap(0, Ordering::Relaxed) {
+		0 => 1,
+		ticks => {
+			restart_periodic();
+			ticks as usize
+		}
+	}
+}
+
+/// Arm a one-shot up to the next timer deadline if it is far enough. Interrupts must be off.
+fn enter() {
+	let (counts_per_tick, max_counts) = match oneshot_limits() {
+		Some(limits) if TICKLESS.load(Ordering::Relaxed) && limits.0 > 0 => limits,
+		_ => return,
+	};
+	let max_ticks = (max_counts / counts_per_tick) as u64;
+	let ticks = match timer::next_deadline() {
+		Some(deadline) => deadline.saturating_sub(clock::ticks()),
+		None => max_ticks,
+	}
+	.min(max_ticks);
+	if ticks < MIN_IDLE_TICKS {
+		return;
+	}
+	ONESHOT_TICKS.store(ticks as u32, Ordering::Relaxed);
+	start_oneshot(ticks as u32 * counts_per_tick);
+}
+
+/// ## Exit
+/// Account for the time slept when an other interrupt woke us before the one-shot,
+/// and go back to periodic mode. \
+/// Every other interrupt handler calls it first, the clock must run while it does its work. \
+/// Interrupts must be off.
+pub fn exit() {
+	let ticks = ONESHOT_TICKS.load(Ordering::Relaxed);
+	if ticks == 0 {
+		return;
+	}
+	let remaining = match oneshot_remaining() {
+		Some(remaining) => remaining,
+		// the pending tick interrupt will account for the whole one-shot
+		None => return,
+	};
+	let counts_per_tick = oneshot_limits().map_or(1, |limits| limits.0);
+	ONESHOT_TICKS.store(0, Ordering::Relaxed);
+	restart_periodic();
+
+	let elapsed = (ticks * counts_per_tick).saturating_sub(remaining)
+		+ LEFTOVER_COUNTS.load(Ordering::Relaxed);
+	LEFTOVER_COUNTS.store(elapsed % counts_per_tick, Ordering::Relaxed);
+	clock::advance((elapsed / counts_per_tick) as usize);
+}
+
+/// ## Idle
+/// Halt until the next interrupt. \
+/// With nothing due in the timer queue for a while, the tick source is put in one-shot
+/// mode so the cpu sleeps until the next deadline instead of waking every tick.
+pub fn idle() {
+	unsafe { asm!("cli") };
+	enter();
+	// sti takes effect after the next instruction, no interrupt is lost before hlt
+	unsafe { asm!("sti", "hlt", "cli") };
+	exit();
+	unsafe { asm!("sti") };
+}
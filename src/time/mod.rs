@@ -0,0 +1,6 @@
+pub mod clock;
+pub mod idle;
+pub mod pit;
+pub mod rtc;
+pub mod timer;
+pub mod tsc;
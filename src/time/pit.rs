@@ -0,0 +1,95 @@
+use crate::include::asm_utile::{inb, outb};
+use crate::include::cmdline;
+use crate::time::clock;
+
+pub const BASE_FREQUENCY: u32 = 1193182; // Base PIT frequency in Hz.
+pub const DESIRED_FREQUENCY: u32 = 100; // Desired timer interrupt frequency in Hz.
+const MIN_FREQUENCY: u32 = 19; // divisor must fit in 16 bits
+const MAX_FREQUENCY: u32 = 1000;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const PORT_B: u16 = 0x61;
+
+const PORT_B_GATE2: u8 = 0x01;
+const PORT_B_SPEAKER: u8 = 0x02;
+const PORT_B_OUT2: u8 = 0x20;
+
+/// Tick rate chosen with `hz=<19-1000>` on the command line, ```DESIRED_FREQUENCY``` otherwise.
+pub fn boot_frequency() -> u32 {
+	cmdline::option("hz")
+		.and_then(|hz| hz.parse::<u32>().ok())
+		.filter(|hz| (MIN_FREQUENCY..=MAX_FREQUENCY).contains(hz))
+		.unwrap_or(DESIRED_FREQUENCY)
+}
+
+/// Program channel 0 as rate generator raising IRQ0 at `frequency` Hz.
+pub unsafe fn configure_pit(frequency: u32) {
+	let divisor = BASE_FREQUENCY / frequency;
+
+	outb(COMMAND, 0x36);
+	outb(CHANNEL0_DATA, (divisor & 0xFF) as u8);
+	outb(CHANNEL0_DATA, (divisor >> 8) as u8);
+	clock::set_frequency(frequency);
+}
+
+/// ## Start_oneshot
+/// Program channel 0 to raise IRQ0 once after `count` PIT cycles. \
+/// ```configure_pit``` goes back to the periodic mode.
+pub unsafe fn start_oneshot(count: u16) {
+	outb(COMMAND, 0x30); // channel 0, lobyte/hibyte, mode 0
+	outb(CHANNEL0_DATA, (count & 0xFF) as u8);
+	outb(CHANNEL0_DATA, (count >> 8) as u8);
+}
+
+/// Current channel 0 counter value.
+pub unsafe fn read_count() -> u16 {
+	outb(COMMAND, 0x00); // latch channel 0
+	let low = inb(CHANNEL0_DATA) as u16;
+	let high = inb(CHANNEL0_DATA) as u16;
+	high << 8 | low
+}
+
+/// True once a one-shot countdown reached zero (OUT pin high).
+pub unsafe fn oneshot_expired() -> bool {
+	outb(COMMAND, 0xE2); // read-back status of channel 0
+	inb(CHANNEL0_DATA) & 0x80 != 0
+}
+
+/// ## Start_channel2
+/// Start a one-shot countdown of `us` microseconds (at most 54925) on channel 2,
+/// with the speaker output disconnected. \
+/// Channel 2 doesn't raise interrupts, poll ```channel2_expired```.
+pub unsafe fn start_channel2(us: u32) {
+	let count = BASE_FREQUENCY as u64 * us as u64 / 1_000_000;
+	assert!(
+		count > 0 && count <= 0xFFFF,
+		"PIT channel 2 countdown is limited to 54925us"
+	);
+
+	outb(PORT_B, (inb(PORT_B) & !PORT_B_SPEAKER) | PORT_B_GATE2);
+	outb(COMMAND, 0xB0); // channel 2, lobyte/hibyte, mode 0
+	outb(CHANNEL2_DATA, (count & 0xFF) as u8);
+	outb(CHANNEL2_DATA, (count >> 8) as u8);
+}
+
+pub unsafe fn channel2_expired() -> bool {
+	inb(PORT_B) & PORT_B_OUT2 != 0
+}
+
+/// ## Start_tone
+/// Run channel 2 as square wave generator at `frequency` Hz and connect it to the speaker. \
+/// Channel 2 is shared with ```start_channel2```, which disconnects the speaker.
+pub unsafe fn start_tone(frequency: u32) {
+	let divisor = (BASE_FREQUENCY / frequency).clamp(1, 0xFFFF);
+
+	outb(COMMAND, 0xB6); // channel 2, lobyte/hibyte, mode 3
+	outb(CHANNEL2_DATA, (divisor & 0xFF) as u8);
+	outb(CHANNEL2_DATA, (divisor >> 8) as u8);
+	outb(PORT_B, inb(PORT_B) | PORT_B_GATE2 | PORT_B_SPEAKER);
+}
+
+pub unsafe fn stop_tone() {
+	outb(PORT_B, inb(PORT_B) & !(PORT_B_GATE2 | PORT_B_SPEAKER));
+}
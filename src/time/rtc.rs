@@ -0,0 +1,335 @@
+use crate::include::asm_utile::{inb, outb};
+use crate::include::cmdline;
+use crate::include::interrupts::{self, without_interrupts};
+use crate::time::clock::{self, Instant, TickSource};
+use core::fmt;
+use spin::Mutex;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+// Not standard, but the ACPI FADT points there on QEMU and most PCs.
+const REG_CENTURY: u8 = 0x32;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const STATUS_B_PERIODIC: u8 = 0x40;
+const STATUS_B_SET: u8 = 0x80;
+const HOUR_PM: u8 = 0x80;
+
+pub const RTC_IRQ: u8 = 8;
+/// Periodic rate 6: 32768 >> (6 - 1) = 1024 Hz.
+const PERIODIC_RATE: u8 = 6;
+
+#[derive(Debug)]
+pub enum RtcError {
+	InvalidDate,
+	InvalidFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+	pub year: u16,
+	pub month: u8,
+	pub day: u8,
+	pub hour: u8,
+	pub minute: u8,
+	pub second: u8,
+}
+
+fn is_leap_year(year: u16) -> bool {
+	(year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+	match month {
+		2 if is_leap_year(year) => 29,
+		2 => 28,
+		4 | 6 | 9 | 11 => 30,
+		_ => 31,
+	}
+}
+
+impl DateTime {
+	pub fn is_valid(&self) -> bool {
+		(1970..=2099).contains(&self.year)
+			&& (1..=12).contains(&self.month)
+			&& self.day >= 1
+			&& self.day <= days_in_month(self.year, self.month)
+			&& self.hour < 24
+			&& self.minute < 60
+			&& self.second < 60
+	}
+
+	/// Seconds since 1970-01-01 00:00:00.
+	pub fn to_unix(self) -> u64 {
+		let mut days: u64 = 0;
+		for year in 1970..self.year {
+			days += if is_leap_year(year) { 366 } else { 365 };
+		}
+		for month in 1..self.month {
+			days += days_in_month(self.year, month) as u64;
+		}
+		days += self.day as u64 - 1;
+		days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+	}
+
+	pub fn from_unix(timestamp: u64) -> DateTime {
+		let mut days = timestamp / 86400;
+		let seconds = timestamp % 86400;
+		let mut year = 1970;
+		loop {
+			let year_days = if is_leap_year(year) { 366 } else { 365 };
+			if days < year_days {
+				break;
+			}
+			days -= year_days;
+			year += 1;
+		}
+		let mut month = 1;
+		while days >= days_in_month(year, month) as u64 {
+			days -= days_in_month(year, month) as u64;
+			month += 1;
+		}
+		DateTime {
+			year,
+			month,
+			day: days as u8 + 1,
+			hour: (seconds / 3600) as u8,
+			minute: (seconds % 3600 / 60) as u8,
+			second: (seconds % 60) as u8,
+		}
+	}
+
+	/// Parse `YYYY-MM-DD HH:MM:SS`.
+	pub fn parse(s: &str) -> Result<DateTime, RtcError> {
+		let (date, time) = s.trim().split_once(' ').ok_or(RtcError::InvalidFormat)?;
+		let mut date = date.split('-');
+		let mut time = time.trim().split(':');
+		fn next<T: core::str::FromStr>(fields: &mut core::str::Split<char>) -> Result<T, RtcError> {
+			fields
+				.next()
+				.and_then(|n| n.parse().ok())
+				.ok_or(RtcError::InvalidFormat)
+		}
+		let datetime = DateTime {
+			year: next(&mut date)?,
+			month: next(&mut date)?,
+			day: next(&mut date)?,
+			hour: next(&mut time)?,
+			minute: next(&mut time)?,
+			second: next(&mut time)?,
+		};
+		if date.next().is_some() || time.next().is_some() {
+			return Err(RtcError::InvalidFormat);
+		}
+		if !datetime.is_valid() {
+			return Err(RtcError::InvalidDate);
+		}
+		Ok(datetime)
+	}
+}
+
+impl fmt::Display for DateTime {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+			self.year, self.month, self.day, self.hour, self.minute, self.second
+		)
+	}
+}
+
+unsafe fn read_register(register: u8) -> u8 {
+	outb(CMOS_ADDRESS, register);
+	inb(CMOS_DATA)
+}
+
+unsafe fn write_register(register: u8, value: u8) {
+	outb(CMOS_ADDRESS, register);
+	outb(CMOS_DATA, value);
+}
+
+fn from_bcd(value: u8, binary: bool) -> u8 {
+	if binary {
+		value
+	} else {
+		(value >> 4) * 10 + (value & 0x0F)
+	}
+}
+
+fn to_bcd(value: u8, binary: bool) -> u8 {
+	if binary {
+		value
+	} else {
+		(value / 10) << 4 | (value % 10)
+	}
+}
+
+#[derive(PartialEq)]
+struct RawTime([u8; 7]);
+
+unsafe fn read_raw() -> RawTime {
+	while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+		core::hint::spin_loop();
+	}
+	RawTime([
+		read_register(REG_SECONDS),
+		read_register(REG_MINUTES),
+		read_register(REG_HOURS),
+		read_register(REG_DAY),
+		read_register(REG_MONTH),
+		read_register(REG_YEAR),
+		read_register(REG_CENTURY),
+	])
+}
+
+/// ## Read
+/// Read the date and time stored in the CMOS. \
+/// The registers are read until two consecutive reads match, so an update
+/// happening in the middle can't give a torn value.
+pub fn read() -> DateTime {
+	without_interrupts(|| unsafe {
+		let mut raw = read_raw();
+		loop {
+			let again = read_raw();
+			if again == raw {
+				break;
+			}
+			raw = again;
+		}
+		let status_b = read_register(REG_STATUS_B);
+		let binary = status_b & STATUS_B_BINARY != 0;
+		let [second, minute, hour, day, month, year, century] = raw.0;
+
+		let mut hour_24 = from_bcd(hour & !HOUR_PM, binary);
+		if status_b & STATUS_B_24_HOUR == 0 {
+			hour_24 %= 12;
+			if hour & HOUR_PM != 0 {
+				hour_24 += 12;
+			}
+		}
+		let century = match from_bcd(century, binary) {
+			c @ 19..=20 => c as u16,
+			_ => 20,
+		};
+		DateTime {
+			year: century * 100 + from_bcd(year, binary) as u16,
+			month: from_bcd(month, binary),
+			day: from_bcd(day, binary),
+			hour: hour_24,
+			minute: from_bcd(minute, binary),
+			second: from_bcd(second, binary),
+		}
+	})
+}
+
+/// ## Write
+/// Store a new date and time in the CMOS, keeping the format (BCD or binary,
+/// 12 or 24 hours) the firmware chose. Updates are frozen while writing.
+pub fn write(datetime: &DateTime) -> Result<(), RtcError> {
+	if !datetime.is_valid() {
+		return Err(RtcError::InvalidDate);
+	}
+	without_interrupts(|| unsafe {
+		let status_b = read_register(REG_STATUS_B);
+		let binary = status_b & STATUS_B_BINARY != 0;
+
+		let hour = if status_b & STATUS_B_24_HOUR == 0 {
+			let pm = if datetime.hour >= 12 { HOUR_PM } else { 0 };
+			let hour_12 = match datetime.hour % 12 {
+				0 => 12,
+				h => h,
+			};
+			to_bcd(hour_12, binary) | pm
+		} else {
+			to_bcd(datetime.hour, binary)
+		};
+
+		write_register(REG_STATUS_B, status_b | STATUS_B_SET);
+		write_register(REG_SECONDS, to_bcd(datetime.second, binary));
+		write_register(REG_MINUTES, to_bcd(datetime.minute, binary));
+		write_register(REG_HOURS, hour);
+		write_register(REG_DAY, to_bcd(datetime.day, binary));
+		write_register(REG_MONTH, to_bcd(datetime.month, binary));
+		write_register(REG_YEAR, to_bcd((datetime.year % 100) as u8, binary));
+		if matches!(from_bcd(read_register(REG_CENTURY), binary), 19..=20) {
+			write_register(REG_CENTURY, to_bcd((datetime.year / 100) as u8, binary));
+		}
+		write_register(REG_STATUS_B, status_b);
+	});
+	sync(datetime);
+	Ok(())
+}
+
+/// ## Enable periodic interrupt
+/// Program the RTC to raise IRQ8 at `32768 >> (rate - 1)` Hz, `rate` in 3..=15. \
+/// Return the resulting frequency.
+pub fn enable_periodic(rate: u8) -> u32 {
+	assert!(
+		(3..=15).contains(&rate),
+		"RTC rate must be between 3 and 15"
+	);
+	without_interrupts(|| unsafe {
+		let status_a = read_register(REG_STATUS_A);
+		write_register(REG_STATUS_A, (status_a & 0xF0) | rate);
+		let status_b = read_register(REG_STATUS_B);
+		write_register(REG_STATUS_B, status_b | STATUS_B_PERIODIC);
+		// a pending interrupt left in C would block the next ones
+		read_register(REG_STATUS_C);
+		interrupts::set_irq_mask(RTC_IRQ, false);
+	});
+	32768 >> (rate - 1)
+}
+
+#[allow(unused)]
+pub fn disable_periodic() {
+	without_interrupts(|| unsafe {
+		let status_b = read_register(REG_STATUS_B);
+		write_register(REG_STATUS_B, status_b & !STATUS_B_PERIODIC);
+		interrupts::set_irq_mask(RTC_IRQ, true);
+	});
+}
+
+/// Read register C, the RTC doesn't raise IRQ8 again until it is read.
+pub fn acknowledge() {
+	unsafe {
+		read_register(REG_STATUS_C);
+	}
+}
+
+// (unix time, instant) of the last RTC read, the wall clock follows the monotonic clock from there.
+static WALL_CLOCK: Mutex<Option<(u64, Instant)>> = Mutex::new(None);
+
+fn sync(datetime: &DateTime) {
+	let instant = Instant::now();
+	without_interrupts(|| *WALL_CLOCK.lock() = Some((datetime.to_unix(), instant)));
+}
+
+/// Current wall clock time, None before ```init```.
+pub fn now() -> Option<DateTime> {
+	let (base, instant) = without_interrupts(|| *WALL_CLOCK.lock())?;
+	Some(DateTime::from_unix(base + instant.elapsed().as_secs()))
+}
+
+/// ## Init RTC
+/// Read the CMOS clock as wall clock base. \
+/// With `tick=rtc` on the command line, the RTC periodic interrupt replaces the PIT as tick source.
+pub fn init() {
+	sync(&read());
+	if cmdline::option("tick") == Some("rtc") {
+		let frequency = enable_periodic(PERIODIC_RATE);
+		clock::set_tick_source(TickSource::Rtc, frequency);
+		without_interrupts(|| interrupts::set_irq_mask(0, true));
+	}
+}
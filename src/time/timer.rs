@@ -0,0 +1,217 @@
+#![allow(unused)]
+
+use crate::include::interrupts::without_interrupts;
+use crate::time::clock::{self, Duration};
+use spin::Mutex;
+
+const MAX_TIMERS: usize = 32;
+
+#[derive(Debug)]
+pub enum TimerError {
+	QueueFull,
+	ZeroPeriod,
+}
+
+/// Callback run from the timer interrupt, with the `data` given at registration.
+pub type TimerCallback = fn(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(usize);
+
+#[derive(Clone, Copy)]
+struct Timer {
+	id: usize,
+	deadline: u64,
+	period: u64,
+	callback: TimerCallback,
+	data: usize,
+}
+
+impl Timer {
+	const EMPTY: Timer = Timer {
+		id: 0,
+		deadline: 0,
+		period: 0,
+		callback: |_| {},
+		data: 0,
+	};
+}
+
+/// Binary min-heap of timers ordered by deadline (in ticks).
+pub struct TimerQueue {
+	heap: [Timer; MAX_TIMERS],
+	len: usize,
+	next_id: usize,
+}
+
+impl TimerQueue {
+	const fn new() -> Self {
+		TimerQueue {
+			heap: [Timer::EMPTY; MAX_TIMERS],
+			len: 0,
+			next_id: 1,
+		}
+	}
+
+	fn sift_up(&mut self, mut index: usize) {
+		while index > 0 {
+			let parent = (index - 1) / 2;
+			if self.heap[parent].deadline <= self.heap[index].deadline {
+				break;
+			}
+			self.heap.swap(parent, index);
+			index = parent;
+		}
+	}
+
+	fn sift_down(&mut self, mut index: usize) {
+		loop {
+			let left = index * 2 + 1;
+			let right = left + 1;
+			let mut smallest = index;
+
+			if left < self.len && self.heap[left].deadline < self.heap[smallest].deadline {
+				smallest = left;
+			}
+			if right < self.len && self.heap[right].deadline < self.heap[smallest].deadline {
+				smallest = right;
+			}
+			if smallest == index {
+				break;
+			}
+			self.heap.swap(smallest, index);
+			index = smallest;
+		}
+	}
+
+	fn push(&mut self, timer: Timer) -> Result<(), TimerError> {
+		if self.len == MAX_TIMERS {
+			return Err(TimerError::QueueFull);
+		}
+		self.heap[self.len] = timer;
+		self.len += 1;
+		self.sift_up(self.len - 1);
+		Ok(())
+	}
+
+	fn remove_at(&mut self, index: usize) -> Timer {
+		let timer = self.heap[index];
+		self.len -= 1;
+		if index != self.len {
+			self.heap[index] = self.heap[self.len];
+			self.sift_down(index);
+			self.sift_up(index);
+		}
+		timer
+	}
+
+	fn add(
+		&mut self,
+		deadline: u64,
+		period: u64,
+		callback: TimerCallback,
+		data: usize,
+	) -> Result<TimerId, TimerError> {
+		let id = self.next_id;
+		self.push(Timer {
+			id,
+			deadline,
+			period,
+			callback,
+			data,
+		})?;
+		self.next_id = self.next_id.wrapping_add(1).max(1);
+		Ok(TimerId(id))
+	}
+
+	fn cancel(&mut self, id: TimerId) -> bool {
+		match self.heap[..self.len].iter().position(|t| t.id == id.0) {
+			Some(index) => {
+				self.remove_at(index);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Take the earliest timer if its deadline passed. \
+	/// A periodic timer is pushed back with its next deadline before return.
+	fn pop_expired(&mut self, now: u64) -> Option<Timer> {
+		if self.len == 0 || self.heap[0].deadline > now {
+			return None;
+		}
+		let timer = self.remove_at(0);
+		if timer.period != 0 {
+			let mut next = timer;
+			next.deadline += timer.period;
+			if next.deadline <= now {
+				// we missed several periods, don't try to catch up
+				next.deadline = now + timer.period;
+			}
+			// the slot we just freed is still available
+			self.push(next).unwrap();
+		}
+		Some(timer)
+	}
+
+	pub fn next_deadline(&self) -> Option<u64> {
+		if self.len == 0 {
+			None
+		} else {
+			Some(self.heap[0].deadline)
+		}
+	}
+}
+
+pub static TIMERS: Mutex<TimerQueue> = Mutex::new(TimerQueue::new());
+
+/// ## Add_oneshot
+/// Run `callback(data)` once from the timer interrupt after `delay`. \
+/// Return a ```TimerId``` usable with ```cancel```.
+pub fn add_oneshot(
+	delay: Duration,
+	callback: TimerCallback,
+	data: usize,
+) -> Result<TimerId, TimerError> {
+	let deadline = clock::ticks() + clock::duration_to_ticks(delay);
+	without_interrupts(|| TIMERS.lock().add(deadline, 0, callback, data))
+}
+
+/// ## Add_periodic
+/// Run `callback(data)` from the timer interrupt every `period` until cancelled.
+pub fn add_periodic(
+	period: Duration,
+	callback: TimerCallback,
+	data: usize,
+) -> Result<TimerId, TimerError> {
+	let period = clock::duration_to_ticks(period);
+	if period == 0 {
+		return Err(TimerError::ZeroPeriod);
+	}
+	let deadline = clock::ticks() + period;
+	without_interrupts(|| TIMERS.lock().add(deadline, period, callback, data))
+}
+
+/// Remove a pending timer. Return false if it already fired (one-shot) or doesn't exist.
+pub fn cancel(id: TimerId) -> bool {
+	without_interrupts(|| TIMERS.lock().cancel(id))
+}
+
+/// Earliest pending deadline in ticks, if any.
+pub fn next_deadline() -> Option<u64> {
+	without_interrupts(|| TIMERS.lock().next_deadline())
+}
+
+/// ## Run_expired
+/// Called from the timer interrupt after the tick. \
+/// The queue lock is released before each callback, so callbacks can add or cancel timers.
+pub fn run_expired() {
+	let now = clock::ticks();
+	loop {
+		let timer = TIMERS.lock().pop_expired(now);
+		match timer {
+			Some(timer) => (timer.callback)(timer.data),
+			None => break,
+		}
+	}
+}
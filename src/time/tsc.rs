@@ -0,0 +1,107 @@
+use crate::include::asm_utile::{cpuid, rdtsc};
+use crate::include::interrupts::without_interrupts;
+use crate::time::pit;
+use spin::Once;
+
+const CPUID_FEATURE_TSC: u32 = 1 << 4;
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+const CALIBRATION_US: u32 = 50_000;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+pub struct Tsc {
+	/// Cycles per second.
+	pub frequency: u64,
+	/// Counter value at calibration, `now_ns` counts from there.
+	base: u64,
+	/// Rate doesn't change with power states (CPUID 0x80000007 EDX bit 8).
+	pub invariant: bool,
+}
+
+static TSC: Once<Tsc> = Once::new();
+
+pub fn get() -> Option<&'static Tsc> {
+	TSC.get()
+}
+
+fn is_available() -> bool {
+	cpuid(1).edx & CPUID_FEATURE_TSC != 0
+}
+
+fn is_invariant() -> bool {
+	cpuid(0x8000_0000).eax >= 0x8000_0007 && cpuid(0x8000_0007).edx & CPUID_INVARIANT_TSC != 0
+}
+
+/// ## Calibrate
+/// Count TSC cycles during a 50ms PIT channel 2 countdown.
+fn calibrate() -> u64 {
+	without_interrupts(|| {
+		unsafe { pit::start_channel2(CALIBRATION_US) };
+		let start = rdtsc();
+		while !unsafe { pit::channel2_expired() } {
+			core::hint::spin_loop();
+		}
+		let end = rdtsc();
+		(end - start) * (1_000_000 / CALIBRATION_US as u64)
+	})
+}
+
+fn cycles_to_nanos(cycles: u64, frequency: u64) -> u64 {
+	(cycles / frequency) * NANOS_PER_SEC + (cycles % frequency) * NANOS_PER_SEC / frequency
+}
+
+/// ## Init TSC
+/// Detect the time stamp counter with CPUID and measure its frequency. \
+/// Without TSC, ```now_ns``` stays at the tick resolution of ```clock```.
+pub fn init() {
+	if !is_available() {
+		return;
+	}
+	let frequency = calibrate();
+	TSC.call_once(|| Tsc {
+		frequency,
+		base: rdtsc(),
+		invariant: is_invariant(),
+	});
+}
+
+/// Nanoseconds since the TSC calibration, falls back to the tick clock without TSC.
+pub fn now_ns() -> u64 {
+	match TSC.get() {
+		Some(tsc) => cycles_to_nanos(rdtsc() - tsc.base, tsc.frequency),
+		None => crate::time::clock::Instant::now().as_nanos(),
+	}
+}
+
+/// ## Ndelay
+/// Busy-wait at least `ns` nanoseconds, usable with interrupts disabled.
+#[allow(unused)]
+pub fn ndelay(ns: u64) {
+	match TSC.get() {
+		Some(tsc) => {
+			let cycles = (ns * (tsc.frequency / 1_000_000)).div_ceil(1000);
+			let start = rdtsc();
+			while rdtsc() - start < cycles {
+				core::hint::spin_loop();
+			}
+		}
+		None => {
+			// PIT channel 2 can count up to 54925us at once
+			let mut remaining = ns.div_ceil(1000);
+			while remaining > 0 {
+				let chunk = remaining.min(50_000);
+				unsafe {
+					pit::start_channel2(chunk as u32);
+					while !pit::channel2_expired() {
+						core::hint::spin_loop();
+					}
+				}
+				remaining -= chunk;
+			}
+		}
+	}
+}
+
+#[allow(unused)]
+pub fn udelay(us: u64) {
+	ndelay(us * 1000);
+}